@@ -1,25 +1,647 @@
-use std::time::{Duration, SystemTime};
+use std::{
+  io,
+  process::ExitStatus,
+  time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context as _, Result};
 
+use clap::ValueEnum;
 use futures::StreamExt as _;
+use nix::{
+  sys::signal::{kill, Signal},
+  unistd::Pid,
+};
 use serde::Serialize;
+use tokio::process::Command;
+use zbus::{names::BusName, zvariant::OwnedValue};
 
 use crate::{
-  protocol::{DbusVigilareProxy, DurationUpdate, Status},
+  helper,
+  inhibitor::InhibitMode,
+  protocol::{
+    Bus, DbusVigilareProxy, DurationUpdate, Instance, Status, Transport,
+    UpdateOptions,
+  },
   signals::ExitSignals,
+  socket,
+  template::{self, Field, Template},
 };
 
-pub async fn msg(update: DurationUpdate) -> Result<(), zbus::Error> {
-  let conn = zbus::Connection::session().await?;
-  let proxy = DbusVigilareProxy::new(&conn).await?;
-  proxy.update(update).await?;
+/// Wait until the daemon has claimed its name on the bus
+pub async fn wait_for_daemon(
+  conn: &zbus::Connection,
+  instance: &Instance,
+  timeout: Duration,
+) -> zbus::Result<()> {
+  let dbus = zbus::fdo::DBusProxy::new(conn).await?;
+  let service_name = instance.service_name();
+  let name = BusName::try_from(service_name.as_str())?;
+
+  let poll = async {
+    while !dbus.name_has_owner(name.clone()).await? {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+  };
+
+  tokio::time::timeout(timeout, poll).await.map_err(|_| {
+    zbus::Error::Failure(format!("timed out waiting for {service_name}"))
+  })?
+}
+
+/// How clients reach the daemon
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConnectArgs {
+  /// How to reach the daemon [default: socket if there is no session
+  /// bus but the daemon's socket exists, otherwise dbus]
+  #[clap(long, value_enum)]
+  transport: Option<Transport>,
+
+  /// The bus the daemon is running on
+  #[clap(long, default_value = "session", value_enum)]
+  bus: Bus,
+
+  /// Wait up to this long for the daemon to appear
+  #[clap(long, value_parser = helper::parse_duration)]
+  wait_daemon: Option<Duration>,
+
+  /// The instance of the daemon to talk to, as it was started with
+  #[clap(long, value_parser = Instance::parse)]
+  instance: Option<Instance>,
+}
+
+impl ConnectArgs {
+  fn instance(&self) -> Instance {
+    self.instance.clone().unwrap_or_default()
+  }
+
+  fn transport(&self) -> Transport {
+    if let Some(transport) = self.transport {
+      return transport;
+    }
+
+    let no_bus = std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none();
+    let socket = socket::default_path(&self.instance());
+    if self.bus == Bus::Session && no_bus && socket.exists() {
+      Transport::Socket
+    } else {
+      Transport::Dbus
+    }
+  }
+
+  pub async fn connect(&self) -> Result<Client> {
+    match self.transport() {
+      Transport::Dbus => {
+        let conn = self.bus.connect().await?;
+        if let Some(timeout) = self.wait_daemon {
+          wait_for_daemon(&conn, &self.instance(), timeout).await?;
+        }
+        Client::over_dbus(&conn, &self.instance()).await
+      }
+      Transport::Socket => {
+        let path = socket::default_path(&self.instance());
+        let Some(timeout) = self.wait_daemon else {
+          return Ok(Client::Socket(socket::Client::connect(&path).await?));
+        };
+
+        let poll = async {
+          loop {
+            match socket::Client::connect(&path).await {
+              Ok(client) => return client,
+              Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+          }
+        };
+        let client =
+          tokio::time::timeout(timeout, poll).await.map_err(|_| {
+            anyhow!("timed out waiting for the daemon on {}", path.display())
+          })?;
+        Ok(Client::Socket(client))
+      }
+    }
+  }
+}
+
+/// A connection to the daemon over either transport, to make any
+/// number of calls over
+pub enum Client {
+  Dbus(DbusVigilareProxy<'static>),
+  Socket(socket::Client),
+}
+
+impl Client {
+  /// Talk to the daemon over a bus connection of the caller's
+  pub async fn over_dbus(
+    conn: &zbus::Connection,
+    instance: &Instance,
+  ) -> Result<Self> {
+    let proxy = DbusVigilareProxy::new(
+      conn,
+      instance.service_name(),
+      instance.object_path(),
+    )
+    .await?;
+    Ok(Self::Dbus(proxy))
+  }
+
+  pub async fn update(
+    &mut self,
+    update: DurationUpdate,
+    options: UpdateOptions,
+  ) -> Result<()> {
+    match self {
+      // plain updates also work with older daemons
+      Self::Dbus(proxy) if options == UpdateOptions::default() => {
+        proxy.update(update).await?
+      }
+      Self::Dbus(proxy) => proxy.update_with(update, options).await?,
+      Self::Socket(client) => client.update(&update, options).await?,
+    }
+    Ok(())
+  }
+
+  /// Start a vigil of the given length, replacing the current one
+  pub async fn set(&mut self, duration: Duration) -> Result<()> {
+    let update = DurationUpdate::Set(duration);
+    self.update(update, Default::default()).await
+  }
+
+  /// End the vigil
+  pub async fn clear(&mut self) -> Result<()> {
+    self.set(Duration::ZERO).await
+  }
+
+  pub async fn extend_by_factor(&mut self, factor: f64) -> Result<()> {
+    match self {
+      Self::Dbus(proxy) => proxy.extend_by_factor(factor).await?,
+      Self::Socket(client) => client.extend_by_factor(factor).await?,
+    }
+    Ok(())
+  }
+
+  /// Switch the daemon to another inhibit mode
+  pub async fn set_mode(&mut self, mode: InhibitMode) -> Result<()> {
+    match self {
+      Self::Dbus(proxy) => proxy.set_mode(mode.as_str()).await?,
+      Self::Socket(client) => client.set_mode(mode.as_str()).await?,
+    }
+    Ok(())
+  }
+
+  /// End the vigil if it carries the label `name`
+  pub async fn cancel(&mut self, name: &str) -> Result<()> {
+    match self {
+      Self::Dbus(proxy) => proxy.cancel(name).await?,
+      Self::Socket(client) => client.cancel(name).await?,
+    }
+    Ok(())
+  }
+
+  /// The running timers by name, with the seconds each has left
+  pub async fn timers(&mut self) -> Result<Vec<(String, u64)>> {
+    match self {
+      Self::Dbus(proxy) => Ok(proxy.timers().await?),
+      Self::Socket(client) => Ok(client.status().await?.timers()),
+    }
+  }
+
+  pub async fn presets(&mut self) -> Result<Vec<Duration>> {
+    let presets = match self {
+      Self::Dbus(proxy) => proxy.presets().await?,
+      Self::Socket(client) => client.presets().await?,
+    };
+    presets
+      .iter()
+      .map(|p| helper::parse_duration(p).map_err(|e| anyhow!(e)))
+      .collect()
+  }
+
+  pub async fn status(&mut self) -> Result<Status> {
+    match self {
+      Self::Dbus(proxy) => {
+        // asked for afresh, as the proxy's cached copy may still be from
+        // before an update just made
+        let inner = proxy.inner();
+        let properties = zbus::Proxy::new(
+          inner.connection(),
+          inner.destination().to_owned(),
+          inner.path().to_owned(),
+          "org.freedesktop.DBus.Properties",
+        )
+        .await?;
+        let status: OwnedValue = properties
+          .call("Get", &(inner.interface().as_str(), "Status"))
+          .await?;
+        Ok(Status::try_from(status)?)
+      }
+      Self::Socket(client) => client.status().await,
+    }
+  }
+
+  /// Subscribe to status changes
+  pub async fn changes(&self) -> Result<Changes> {
+    match self {
+      Self::Dbus(proxy) => {
+        Ok(Changes::Dbus(proxy.receive_status_changed().await))
+      }
+      Self::Socket(client) => {
+        let mut subscription = client.subscribe().await?;
+        // the first one is the status we already have
+        subscription.next().await?;
+        Ok(Changes::Socket(subscription))
+      }
+    }
+  }
+}
+
+/// Notifications of status changes
+pub enum Changes {
+  Dbus(zbus::proxy::PropertyStream<'static, Status>),
+  Socket(socket::Subscription),
+}
+
+impl Changes {
+  /// Wait for the next change, None once the daemon is gone
+  pub async fn next(&mut self) -> Option<()> {
+    match self {
+      Self::Dbus(stream) => stream.next().await.map(|_| ()),
+      Self::Socket(subscription) => subscription.next().await.ok().map(|_| ()),
+    }
+  }
+}
+
+/// What `msg` prints once the update went through
+#[derive(clap::Args, Debug, Clone, Copy, PartialEq, Default)]
+pub struct OutputArgs {
+  /// Print the resulting status as JSON, in the same shape as the
+  /// monitor output
+  #[clap(long)]
+  json: bool,
+
+  /// Print a one-line summary of the resulting status
+  #[clap(long, conflicts_with = "json")]
+  summary: bool,
+}
+
+pub async fn msg(
+  update: DurationUpdate,
+  options: UpdateOptions,
+  output: &OutputArgs,
+  args: &ConnectArgs,
+) -> Result<()> {
+  let mut client = args.connect().await?;
+  client.update(update, options).await?;
+  if !output.json && !output.summary {
+    return Ok(());
+  }
+
+  let report =
+    StatusReport::new_from_client(&mut client, FormatArgs::default()).await?;
+  if output.json {
+    report.print();
+  } else {
+    println!("{}", report.summary());
+  }
   Ok(())
 }
 
+pub async fn stop(args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  client.clear().await
+}
+
+pub async fn extend(factor: f64, args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  client.extend_by_factor(factor).await
+}
+
+// how long the vigil held by `run` lasts unless renewed, so that it
+// runs out soon after a wrapper that died without clearing it
+const RUN_LEASE: Duration = Duration::from_secs(60);
+
+/// Hold a vigil for as long as `command` runs, then clear it. Exit
+/// signals are passed on to the command. Returns its exit status.
+pub async fn run(command: &[String], args: &ConnectArgs) -> Result<ExitStatus> {
+  let [program, program_args @ ..] = command else {
+    return Err(anyhow!("no command to run"));
+  };
+
+  let mut client = args.connect().await?;
+  let mut lease = Lease::new(&mut client, format!("run {program}")).await?;
+  lease.renew(&mut client).await?;
+
+  let mut child = match Command::new(program).args(program_args).spawn() {
+    Ok(child) => child,
+    Err(e) => {
+      lease.release(&mut client).await?;
+      return Err(e).with_context(|| format!("failed to run {program}"));
+    }
+  };
+
+  // the command runs its course whatever goes wrong here, so that its
+  // status is never lost
+  let mut exit_signals = ExitSignals::new();
+  let mut renew = tokio::time::interval(RUN_LEASE / 2);
+  renew.tick().await;
+  let status = loop {
+    tokio::select! {
+      status = child.wait() => break status?,
+      _ = renew.tick() => {
+        if let Err(e) = lease.renew(&mut client).await {
+          eprintln!("Failed to renew the vigil: {e:#}");
+        }
+      }
+      signal = exit_signals.recv() => {
+        let Some(pid) = child.id() else { continue };
+        let passed_on = Signal::try_from(signal.as_raw_value())
+          .map_err(anyhow::Error::from)
+          .and_then(|signal| Ok(kill(Pid::from_raw(pid as i32), signal)?));
+        if let Err(e) = passed_on {
+          eprintln!("Failed to pass on the signal to {program}: {e:#}");
+        }
+      }
+    }
+  };
+
+  if let Err(e) = lease.release(&mut client).await {
+    eprintln!("Failed to release the vigil: {e:#}");
+  }
+  Ok(status)
+}
+
+// The vigil `run` holds: at least RUN_LEASE from now, on top of any
+// vigil there was before, which it goes back to when released
+struct Lease {
+  label: String,
+  // the deadline of the vigil before, None if there was none
+  before: Option<u64>,
+  // the deadline last set, None while the vigil there is long enough
+  held: Option<u64>,
+}
+
+impl Lease {
+  async fn new(client: &mut Client, label: String) -> Result<Self> {
+    let status = client.status().await?;
+    let before = status.active.then_some(status.wake_until);
+    Ok(Self {
+      label,
+      before,
+      held: None,
+    })
+  }
+
+  async fn renew(&mut self, client: &mut Client) -> Result<()> {
+    let status = client.status().await?;
+    let lasts_until = unix_now() + RUN_LEASE.as_secs();
+    if status.indefinite()
+      || (status.active && status.wake_until >= lasts_until)
+    {
+      return Ok(());
+    }
+
+    // a vigil that's there already keeps its label
+    let options = UpdateOptions {
+      label: if status.active {
+        String::new()
+      } else {
+        self.label.clone()
+      },
+      ..Default::default()
+    };
+    client
+      .update(DurationUpdate::Set(RUN_LEASE), options)
+      .await?;
+    self.held = Some(client.status().await?.wake_until);
+    Ok(())
+  }
+
+  // Go back to the vigil there was before, unless someone else changed
+  // the vigil since
+  async fn release(&self, client: &mut Client) -> Result<()> {
+    let Some(held) = self.held else {
+      return Ok(());
+    };
+    let status = client.status().await?;
+    if !status.active || status.wake_until != held {
+      return Ok(());
+    }
+
+    let now = unix_now();
+    match self.before {
+      Some(before) if before > now => {
+        client.set(Duration::from_secs(before - now)).await
+      }
+      _ => client.clear().await,
+    }
+  }
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+pub async fn set_mode(mode: InhibitMode, args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  client.set_mode(mode).await
+}
+
+#[derive(Serialize)]
+struct TimerReport {
+  name: String,
+  // None when it has no deadline
+  remaining_seconds: Option<u64>,
+}
+
+/// Print the running timers, one per line or as a JSON array
+pub async fn list(json: bool, args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  let timers = client.timers().await?.into_iter().map(|(name, left)| {
+    let remaining_seconds = (left != u64::MAX).then_some(left);
+    TimerReport {
+      name,
+      remaining_seconds,
+    }
+  });
+
+  if json {
+    let timers: Vec<_> = timers.collect();
+    println!("{}", serde_json::to_string(&timers)?);
+    return Ok(());
+  }
+  for timer in timers {
+    let name = if timer.name.is_empty() {
+      "-"
+    } else {
+      &timer.name
+    };
+    let left = match timer.remaining_seconds {
+      Some(secs) => helper::format_duration(Duration::from_secs(secs)),
+      None => "∞".to_string(),
+    };
+    println!("{name}\t{left}");
+  }
+  Ok(())
+}
+
+pub async fn cancel(name: &str, args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  client.cancel(name).await
+}
+
+/// Move the vigil on to the next of the presets, ending it after the
+/// last one
+pub async fn cycle(presets: &[Duration], args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  let presets = match presets {
+    [] => client.presets().await?,
+    presets => presets.to_vec(),
+  };
+  let status = client.status().await?;
+  match next_preset(&status, &presets) {
+    Some(next) => client.set(next).await,
+    None => client.clear().await,
+  }
+}
+
+pub async fn toggle(duration: Duration, args: &ConnectArgs) -> Result<()> {
+  let unreachable = "cannot reach the daemon, is `vigilare daemon` running?";
+  let mut client = args.connect().await.context(unreachable)?;
+  let status = client.status().await.context(unreachable)?;
+  if status.active {
+    client.clear().await
+  } else {
+    client.set(duration).await
+  }
+}
+
+// The vigil's length so far tells which preset it is on. Setting a
+// preset keeps the start time, so the length only grows as we cycle.
+fn next_preset(status: &Status, presets: &[Duration]) -> Option<Duration> {
+  if !status.active {
+    return presets.iter().copied().min();
+  }
+  if status.indefinite() {
+    return None;
+  }
+
+  // allow for the timestamps being whole seconds
+  let length = status.wake_until.saturating_sub(status.started_at) + 2;
+  let length = Duration::from_secs(length);
+  presets.iter().copied().filter(|&p| p > length).min()
+}
+
+/// How the minute figure in the status message is rounded
+#[derive(Clone, Copy, Debug, PartialEq, Default, ValueEnum)]
+pub enum Rounding {
+  #[default]
+  Up,
+  Down,
+  Nearest,
+}
+
+impl Rounding {
+  fn minutes(&self, secs: u64) -> u64 {
+    match self {
+      Self::Up => secs.div_ceil(60),
+      Self::Down => secs / 60,
+      Self::Nearest => (secs + 30) / 60,
+    }
+  }
+
+  // Seconds until the minute figure for `secs` changes
+  fn next_change(&self, secs: u64) -> u64 {
+    match self {
+      Self::Up => match secs % 60 {
+        0 => 60,
+        rem => rem,
+      },
+      Self::Down => secs % 60 + 1,
+      Self::Nearest => (secs + 30) % 60 + 1,
+    }
+  }
+}
+
+/// How the monitor renders the status message
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct FormatArgs {
+  /// How to round the remaining minutes shown in the message
+  #[clap(long, default_value = "up", value_enum)]
+  round: Rounding,
+
+  /// Only count down in the message within this long of the vigil
+  /// starting or ending
+  #[clap(long, value_parser = helper::parse_duration)]
+  show_threshold: Option<Duration>,
+
+  /// What the message shows for an active vigil outside of
+  /// --show-threshold [default: nothing]
+  #[clap(long, default_value = "", requires = "show_threshold")]
+  active_glyph: String,
+
+  /// Print this instead of JSON, with {remaining}, {remaining_seconds},
+  /// {state}, {wake_until_local} and {label} filled in, e.g.
+  /// "{remaining} ({state})"
+  #[clap(long, visible_alias = "format", value_parser = Template::parse)]
+  format_template: Option<Template>,
+}
+
+impl FormatArgs {
+  // Seconds until the vigil that started at `started_at` leaves the
+  // --show-threshold window after its start, if it's still in there
+  fn start_window_left(&self, started_at: u64) -> Option<u64> {
+    let threshold = self.show_threshold?.as_secs();
+    let now = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    let elapsed = now.saturating_sub(started_at);
+    (elapsed < threshold).then(|| threshold - elapsed)
+  }
+
+  // Whether the countdown is hidden with `secs` remaining
+  fn hides(&self, secs: u64, started_at: u64) -> bool {
+    let Some(threshold) = self.show_threshold else {
+      return false;
+    };
+    secs > threshold.as_secs() && self.start_window_left(started_at).is_none()
+  }
+
+  // The message for a vigil that started at `started_at` and has
+  // `secs` left
+  fn countdown(&self, secs: u64, started_at: u64) -> String {
+    if self.hides(secs, started_at) {
+      return self.active_glyph.clone();
+    }
+    // a minute figure says little about a short countdown
+    if secs < 60 {
+      return format!("{secs}s");
+    }
+    format!("{}m", self.round.minutes(secs))
+  }
+}
+
 #[derive(Serialize, Debug, Clone, PartialEq, Default)]
 struct StatusReport {
   active: bool,
   remaining_seconds: Option<u64>,
+  // UNIX epoch time the vigil began, 0 when inactive
+  started_at: u64,
+  // the same for when it ends, 0 when it has no deadline
+  #[serde(skip)]
+  wake_until: u64,
+  // what asked for the vigil
+  label: Option<String>,
+  // UNIX epoch time the last vigil ran out, 0 if it didn't
+  last_expired_at: u64,
+  // the modes that failed to inhibit, with why
+  degraded: Option<String>,
   message: String,
+  #[serde(skip)]
+  format: FormatArgs,
 }
 
 impl StatusReport {
@@ -27,81 +649,143 @@ impl StatusReport {
     serde_json::to_string(&self).expect("failed to serialize report")
   }
 
-  fn from_status(msg: Status) -> Self {
+  fn from_status(msg: Status, format: FormatArgs) -> Self {
     let epoch = Duration::from_secs(msg.wake_until);
     let now = SystemTime::now();
     let duration = (SystemTime::UNIX_EPOCH + epoch)
       .duration_since(now)
       .unwrap_or_default();
 
-    let remaining_min = duration.as_secs_f32() / 60.0;
-    let message = if msg.active {
-      format!("{}m", remaining_min.ceil() as u64)
-    } else {
-      String::default()
-    };
+    // count partial seconds as a whole one, so that both figures
+    // below are derived from the same value
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+
+    let remaining_seconds = (msg.active && !msg.indefinite()).then_some(secs);
 
-    let remaining_seconds = msg.active.then_some((remaining_min * 60.0) as u64);
+    let message = match remaining_seconds {
+      Some(secs) => format.countdown(secs, msg.started_at),
+      None if msg.indefinite() => "∞".to_string(),
+      None => String::default(),
+    };
 
     Self {
       active: msg.active,
       remaining_seconds,
+      started_at: msg.started_at,
+      wake_until: if msg.active { msg.wake_until } else { 0 },
+      label: (!msg.label.is_empty()).then_some(msg.label),
+      last_expired_at: msg.last_expired_at,
+      degraded: (!msg.degraded.is_empty()).then_some(msg.degraded),
       message,
+      format,
     }
   }
 
   fn next_check_duration(&self) -> Duration {
-    match self.remaining_seconds {
-      None => Duration::MAX,
-      Some(secs) if secs % 60 == 0 => Duration::from_secs(60),
-      Some(secs) => Duration::from_secs(secs % 60),
+    let Some(secs) = self.remaining_seconds else {
+      return Duration::MAX;
+    };
+
+    let mut next = match secs {
+      // counting down by the second, see `from_status`
+      ..=60 => 1,
+      _ => self.format.round.next_change(secs),
+    };
+    // the countdown may have to disappear before the figure changes
+    if let Some(left) = self.format.start_window_left(self.started_at) {
+      next = next.min(left);
     }
+    Duration::from_secs(next)
   }
 
-  async fn update(
-    &mut self,
-    proxy: &DbusVigilareProxy<'_>,
-  ) -> zbus::Result<()> {
-    let status = proxy.status().await?;
-    let report = StatusReport::from_status(status);
+  async fn update(&mut self, client: &mut Client) -> Result<()> {
+    let status = client.status().await?;
+    let report = StatusReport::from_status(status, self.format.clone());
     *self = report;
     Ok(())
   }
 
-  async fn new_from_proxy(proxy: &DbusVigilareProxy<'_>) -> zbus::Result<Self> {
-    let status = proxy.status().await?;
-    Ok(Self::from_status(status))
+  async fn new_from_client(
+    client: &mut Client,
+    format: FormatArgs,
+  ) -> Result<Self> {
+    let status = client.status().await?;
+    Ok(Self::from_status(status, format))
   }
 
   fn print(&self) {
-    println!("{}", self.json());
+    match &self.format.format_template {
+      Some(template) => println!("{}", template.render(|f| self.field(f))),
+      None => println!("{}", self.json()),
+    }
+  }
+
+  fn field(&self, field: Field) -> String {
+    match field {
+      Field::Remaining => self.message.clone(),
+      Field::RemainingSeconds => self
+        .remaining_seconds
+        .map(|s| s.to_string())
+        .unwrap_or_default(),
+      Field::State => match (self.active, self.remaining_seconds) {
+        (false, _) => "inactive",
+        (true, None) => "indefinite",
+        (true, Some(_)) => "active",
+      }
+      .to_string(),
+      Field::WakeUntilLocal => match self.wake_until {
+        0 => String::new(),
+        at => template::local_time(at),
+      },
+      Field::Label => self.label.clone().unwrap_or_default(),
+      // not offered to the monitor, see `Template::parse`
+      Field::Mode => String::new(),
+    }
+  }
+
+  fn summary(&self) -> String {
+    let summary = match self.remaining_seconds {
+      _ if !self.active => "no vigil".to_string(),
+      Some(_) => format!("vigil set: {} remaining", self.message),
+      None => "vigil set: indefinitely".to_string(),
+    };
+    match &self.degraded {
+      Some(degraded) => format!("{summary} (degraded, {degraded})"),
+      None => summary,
+    }
   }
 }
 
-async fn monitor() -> zbus::Result<()> {
-  let conn = zbus::Connection::session().await?;
-  let proxy = DbusVigilareProxy::new(&conn).await?;
-  let mut report = StatusReport::new_from_proxy(&proxy).await?;
-  report.print();
+// The first wait before reconnecting to a daemon that went away,
+// doubled on every failed attempt up to the maximum
+const MONITOR_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_MONITOR_BACKOFF: Duration = Duration::from_secs(60);
 
-  let mut stream = proxy.receive_status_changed().await;
+// Print the status until the daemon goes away, resetting `backoff`
+// once connected
+async fn monitor(
+  format: &FormatArgs,
+  args: &ConnectArgs,
+  backoff: &mut Duration,
+) -> Result<()> {
+  let mut client = args.connect().await?;
+  let mut report =
+    StatusReport::new_from_client(&mut client, format.clone()).await?;
+  report.print();
+  *backoff = MONITOR_BACKOFF;
 
-  let mut exit_signals = ExitSignals::new();
+  let mut changes = client.changes().await?;
 
   loop {
     tokio::select! {
-      _ = exit_signals.recv() => {
-        eprintln!("Received exit signal, exiting");
-        return Ok(());
-      }
-      Some(_) = stream.next() => {
-        report.update(&proxy).await?;
+      Some(_) = changes.next() => {
+        report.update(&mut client).await?;
       }
       _ = tokio::time::sleep(report.next_check_duration()) => {
-        report.update(&proxy).await?;
+        report.update(&mut client).await?;
       }
       else => {
-        eprintln!("Dbus stream closed");
+        eprintln!("Status stream closed");
         return Ok(());
       }
     }
@@ -110,14 +794,111 @@ async fn monitor() -> zbus::Result<()> {
   }
 }
 
-pub async fn monitor_forever() -> zbus::Result<()> {
+pub async fn status(format: &FormatArgs, args: &ConnectArgs) -> Result<()> {
+  let mut client = args.connect().await?;
+  let report =
+    StatusReport::new_from_client(&mut client, format.clone()).await?;
+  report.print();
+  Ok(())
+}
+
+pub async fn monitor_forever(
+  format: &FormatArgs,
+  args: &ConnectArgs,
+) -> Result<()> {
+  let mut args = args.clone();
+  let mut backoff = MONITOR_BACKOFF;
+  // for the whole run, as the default handlers are gone once replaced
+  let mut exit_signals = ExitSignals::new();
+
   loop {
-    match monitor().await {
-      Ok(_) => continue,
-      Err(zbus::Error::MethodError(_, _, _)) => {
-        tokio::time::sleep(Duration::from_secs(5)).await
+    let result = tokio::select! {
+      result = monitor(format, &args, &mut backoff) => result,
+      _ = exit_signals.recv() => {
+        eprintln!("Received exit signal, exiting");
+        return Ok(());
       }
+    };
+    // only wait for the daemon on the first connection, later
+    // reconnects keep retrying below
+    args.wait_daemon = None;
+
+    match result {
+      Ok(()) => continue,
+      Err(e) if daemon_gone(&e) => {}
       Err(e) => return Err(e),
     }
+
+    tokio::select! {
+      _ = tokio::time::sleep(backoff) => {}
+      _ = exit_signals.recv() => {
+        eprintln!("Received exit signal, exiting");
+        return Ok(());
+      }
+    }
+    backoff = (backoff * 2).min(MAX_MONITOR_BACKOFF);
+  }
+}
+
+// Whether an error means the daemon isn't there (yet), as opposed to
+// something retrying won't fix
+fn daemon_gone(e: &anyhow::Error) -> bool {
+  matches!(
+    e.downcast_ref::<zbus::Error>(),
+    Some(zbus::Error::MethodError(_, _, _))
+  ) || e.downcast_ref::<io::Error>().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rounds_minutes_around_a_minute() {
+    let minutes = |round: Rounding| [59, 60, 61].map(|s| round.minutes(s));
+    assert_eq!(minutes(Rounding::Up), [1, 1, 2]);
+    assert_eq!(minutes(Rounding::Down), [0, 1, 1]);
+    assert_eq!(minutes(Rounding::Nearest), [1, 1, 1]);
+    assert_eq!(Rounding::Nearest.minutes(90), 2);
+  }
+
+  #[test]
+  fn counts_down_by_the_second_under_a_minute() {
+    let format = FormatArgs::default();
+    let message = |secs| format.countdown(secs, 0);
+    assert_eq!(message(1), "1s");
+    assert_eq!(message(30), "30s");
+    assert_eq!(message(59), "59s");
+    assert_eq!(message(60), "1m");
+    assert_eq!(message(61), "2m");
+
+    for secs in [1, 30, 59] {
+      let report = StatusReport {
+        remaining_seconds: Some(secs),
+        ..Default::default()
+      };
+      assert_eq!(report.next_check_duration(), Duration::from_secs(1));
+    }
+  }
+
+  #[test]
+  fn next_change_is_when_the_minute_figure_changes() {
+    for round in Rounding::value_variants() {
+      for secs in [59, 60, 61, 89, 90, 3_600] {
+        let next = round.next_change(secs);
+        assert_eq!(
+          round.minutes(secs + 1 - next),
+          round.minutes(secs),
+          "{round:?} at {secs}s changes before {next}s"
+        );
+        if next <= secs {
+          assert_ne!(
+            round.minutes(secs - next),
+            round.minutes(secs),
+            "{round:?} at {secs}s doesn't change after {next}s"
+          );
+        }
+      }
+    }
   }
 }
@@ -0,0 +1,451 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+  config_file::{self, ConfigFile},
+  helper,
+  inhibitor::{self, Category, InhibitMode},
+  notify::Urgency,
+  protocol::{Bus, Instance, Transport},
+  template::Template,
+};
+
+/// Settings used by the daemon, resolved from defaults, the config
+/// file and command line flags.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+  pub transport: Transport,
+  pub bus: Bus,
+  // which of several daemons on the bus this is
+  pub instance: Instance,
+  // held together when there is more than one
+  pub modes: Vec<InhibitMode>,
+  // hold only the first of the modes that inhibits
+  pub fallback: bool,
+  pub category: Option<Category>,
+  // colon-separated logind inhibit scopes, overriding the category
+  pub logind_what: Option<String>,
+  // colon-separated portal inhibit flags, overriding the category
+  pub portal_flags: Option<String>,
+  pub app_id: String,
+  #[serde(with = "helper::duration_str")]
+  pub xscreensaver_interval: Duration,
+  #[serde(with = "helper::duration_str")]
+  pub mouse_jitter_interval: Duration,
+  #[serde(with = "helper::option_duration_str")]
+  pub reassert_interval: Option<Duration>,
+  #[serde(with = "helper::option_duration_str")]
+  pub idle_timeout: Option<Duration>,
+  #[serde(with = "helper::option_duration_str")]
+  pub max_extend: Option<Duration>,
+  #[serde(with = "helper::option_duration_str")]
+  pub max_duration: Option<Duration>,
+  // notify this long before the vigil ends
+  #[serde(with = "helper::option_duration_str")]
+  pub warn_before: Option<Duration>,
+  // how notifications look, and whether one stays up during a vigil
+  pub notify_urgency: Urgency,
+  pub notify_summary: Option<Template>,
+  pub notify_body: Option<Template>,
+  pub notify_active: bool,
+  // how often activity watchers re-check their condition
+  #[serde(with = "helper::duration_str")]
+  pub watch_interval: Duration,
+  #[serde(with = "helper::option_duration_str")]
+  pub startup_delay: Option<Duration>,
+  // offered to front-ends and `vigilare cycle`
+  #[serde(with = "helper::durations_str")]
+  pub presets: Vec<Duration>,
+  // let go while the session is locked
+  pub release_on_lock: bool,
+  // carry on without the D-Bus service if it goes away
+  pub keep_alive: bool,
+  // where to serve Prometheus metrics, if anywhere
+  pub metrics_addr: Option<SocketAddr>,
+  // re-arm a vigil that ran out for its original length
+  pub repeat: bool,
+  // stay awake while a process of this name runs
+  pub while_process: Option<String>,
+  // stay awake while this shell command exits with success
+  pub while_command: Option<String>,
+  // stay awake while the network moves more than `network_threshold`
+  // bytes per second, and for `network_quiet` after
+  pub inhibit_network: bool,
+  pub network_threshold: u64,
+  #[serde(with = "helper::duration_str")]
+  pub network_quiet: Duration,
+  // keep inhibiting this long after the guards above let go
+  #[serde(with = "helper::option_duration_str")]
+  pub guard_hold: Option<Duration>,
+  pub clock: Clock,
+}
+
+/// The clock a vigil counts down on
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Clock {
+  /// Time the machine is up for: unaffected by changes to the wall
+  /// clock, and stands still while suspended
+  #[default]
+  Monotonic,
+  /// The wall clock: keeps counting down while suspended, and follows
+  /// when the clock is set, e.g. by NTP
+  Realtime,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      transport: Transport::Dbus,
+      bus: Bus::Session,
+      instance: Instance::default(),
+      modes: vec![InhibitMode::MouseJitter],
+      fallback: false,
+      category: None,
+      logind_what: None,
+      portal_flags: None,
+      app_id: "vigilare".to_string(),
+      xscreensaver_interval: Duration::from_secs(60),
+      mouse_jitter_interval: Duration::from_secs(60),
+      reassert_interval: None,
+      idle_timeout: None,
+      max_extend: None,
+      max_duration: None,
+      warn_before: None,
+      notify_urgency: Urgency::Normal,
+      notify_summary: None,
+      notify_body: None,
+      notify_active: false,
+      watch_interval: Duration::from_secs(5),
+      startup_delay: None,
+      presets: [15, 30, 60]
+        .map(|minutes| Duration::from_secs(minutes * 60))
+        .to_vec(),
+      release_on_lock: false,
+      keep_alive: false,
+      metrics_addr: None,
+      repeat: false,
+      while_process: None,
+      while_command: None,
+      inhibit_network: false,
+      network_threshold: 50_000,
+      network_quiet: Duration::from_secs(30),
+      guard_hold: None,
+      clock: Clock::Monotonic,
+    }
+  }
+}
+
+impl Config {
+  pub fn json(&self) -> String {
+    serde_json::to_string(&self).expect("failed to serialize config")
+  }
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+  /// Read settings from this TOML file, under the flags given
+  /// [default: $XDG_CONFIG_HOME/vigilare/config.toml]
+  #[clap(long)]
+  config_file: Option<PathBuf>,
+
+  /// How to serve the control interface [default: dbus]
+  #[clap(long, value_enum)]
+  transport: Option<Transport>,
+
+  /// The bus to serve the control interface on [default: session]
+  #[clap(long, value_enum)]
+  bus: Option<Bus>,
+
+  /// Run as a separate instance with its own bus name, object path and
+  /// files, for several daemons on one bus. Clients pass the same
+  #[clap(long, value_parser = Instance::parse)]
+  instance: Option<Instance>,
+
+  /// Inhibit mechanism, repeat or separate with commas to use several
+  /// at once [default: mouse-jitter]
+  #[clap(
+    short,
+    long = "mode",
+    env = "VIGILARE_MODE",
+    value_enum,
+    value_delimiter = ','
+  )]
+  modes: Vec<InhibitMode>,
+
+  /// Of several modes, hold only the first that manages to inhibit
+  /// instead of all of them, e.g. `--mode xfce,logind,xset --fallback`
+  #[clap(long)]
+  fallback: bool,
+
+  /// What to inhibit, if the mode supports choosing
+  #[clap(long, visible_alias = "while", value_enum)]
+  category: Option<Category>,
+
+  /// Colon-separated list of logind inhibit scopes, e.g. "sleep:idle".
+  /// Takes precedence over --category in logind mode
+  #[clap(long, value_parser = inhibitor::parse_logind_what)]
+  logind_what: Option<String>,
+
+  /// Colon-separated list of what the portal should inhibit, from
+  /// logout, user-switch, suspend and idle. Takes precedence over
+  /// --category in portal mode
+  #[clap(long, value_parser = inhibitor::parse_portal_flags)]
+  portal_flags: Option<String>,
+
+  /// Application name to register inhibitions under [default: vigilare]
+  #[clap(long)]
+  app_id: Option<String>,
+
+  /// Interval between `xset s reset` calls [default: 1m]
+  #[clap(long, value_parser = helper::parse_duration)]
+  xscreensaver_interval: Option<Duration>,
+
+  /// Interval between mouse position checks [default: 1m]
+  #[clap(long, value_parser = helper::parse_duration)]
+  mouse_jitter_interval: Option<Duration>,
+
+  /// Re-establish the inhibitor at this interval during a vigil, in
+  /// case it was released behind our back
+  #[clap(long, value_parser = helper::parse_duration)]
+  reassert_interval: Option<Duration>,
+
+  /// Consider the user idle after this long without input. Needs a
+  /// Wayland compositor supporting ext-idle-notify-v1.
+  #[clap(long, value_parser = helper::parse_duration)]
+  idle_timeout: Option<Duration>,
+
+  /// Limit how much a single "+" message can extend the vigil by
+  #[clap(long, value_parser = helper::parse_duration)]
+  max_extend: Option<Duration>,
+
+  /// Never let the vigil run for longer than this from now
+  #[clap(long, value_parser = helper::parse_duration)]
+  max_duration: Option<Duration>,
+
+  /// Show a desktop notification this long before the vigil ends, so
+  /// that there's time to extend it
+  #[clap(long, value_parser = helper::parse_duration)]
+  warn_before: Option<Duration>,
+
+  /// The urgency of the notifications shown [default: normal]
+  #[clap(long, value_enum)]
+  notify_urgency: Option<Urgency>,
+
+  /// The summary line of the notifications shown, with placeholders
+  /// like those of --format-template and {mode}, e.g.
+  /// "Awake for {remaining}"
+  #[clap(long, value_parser = Template::parse_notification)]
+  notify_summary: Option<Template>,
+
+  /// The text of the notifications shown, with the same placeholders
+  #[clap(long, value_parser = Template::parse_notification)]
+  notify_body: Option<Template>,
+
+  /// Keep a notification up for as long as a vigil runs, updated
+  /// whenever the vigil changes
+  #[clap(long)]
+  notify_active: bool,
+
+  /// How often activity watchers re-check whether to keep the machine
+  /// awake [default: 5s]
+  #[clap(long, visible_alias = "poll-interval", value_parser = helper::parse_duration)]
+  watch_interval: Option<Duration>,
+
+  /// Wait this long before serving, for session startups where the
+  /// bus comes up late. Unreachable buses are retried regardless
+  #[clap(long, value_parser = helper::parse_duration)]
+  startup_delay: Option<Duration>,
+
+  /// Comma-separated preset durations offered to front-ends and
+  /// `vigilare cycle` [default: 15m,30m,1h]
+  #[clap(long, value_delimiter = ',', value_parser = helper::parse_duration)]
+  presets: Vec<Duration>,
+
+  /// Release the inhibitor while the session is locked, as reported
+  /// by logind. The countdown pauses until it's unlocked
+  #[clap(long)]
+  release_on_lock: bool,
+
+  /// Keep running if the D-Bus service goes away, e.g. when the bus
+  /// restarts, instead of exiting. The vigil then runs its course and
+  /// --while-process and the other watchers still apply
+  #[clap(long)]
+  keep_alive: bool,
+
+  /// Serve Prometheus metrics over HTTP at this address, e.g.
+  /// 127.0.0.1:9099
+  #[clap(long)]
+  metrics_addr: Option<SocketAddr>,
+
+  /// When a vigil runs out, release the inhibitor and start it over
+  /// with the length it was first given, so that it never ends on its
+  /// own. Meant for test rigs
+  #[clap(long, visible_alias = "reset-on-deadline")]
+  repeat: bool,
+
+  /// Keep the machine awake while a process of this name is running,
+  /// on top of any vigil. Checked at startup and every
+  /// --watch-interval after
+  #[clap(long)]
+  while_process: Option<String>,
+
+  /// Keep the machine awake while this shell command exits with
+  /// success, e.g. 'mpc status | grep -q playing'. Run with `sh -c`
+  /// at startup and every --watch-interval after
+  #[clap(long)]
+  while_command: Option<String>,
+
+  /// Keep the machine awake while a network transfer is going on, as
+  /// seen in the interface byte counters every --watch-interval
+  #[clap(long)]
+  inhibit_network: bool,
+
+  /// Bytes per second received and sent together that count as a
+  /// transfer [default: 50000]
+  #[clap(long, requires = "inhibit_network")]
+  network_threshold: Option<u64>,
+
+  /// How long the network has to stay below the threshold before the
+  /// machine may sleep [default: 30s]
+  #[clap(long, requires = "inhibit_network", value_parser = helper::parse_duration)]
+  network_quiet: Option<Duration>,
+
+  /// Keep the machine awake this long after --while-process,
+  /// --while-command or --inhibit-network last wanted it awake, so
+  /// that a condition flapping on and off doesn't flap the inhibitor
+  /// too
+  #[clap(long, value_parser = helper::parse_duration)]
+  guard_hold: Option<Duration>,
+
+  /// The clock vigils count down on [default: monotonic]
+  #[clap(long, value_enum)]
+  clock: Option<Clock>,
+}
+
+impl ConfigArgs {
+  /// Apply the config file over the defaults, then the flags given on
+  /// the command line over both
+  pub fn resolve(&self) -> Result<Config> {
+    let mut config = Config::default();
+
+    // only one asked for explicitly has to exist
+    let explicit = self.config_file.is_some();
+    let path = self.config_file.clone().or_else(config_file::default_path);
+    if let Some(path) = path {
+      match ConfigFile::load(&path)? {
+        Some(file) => file.apply(&mut config)?,
+        None if explicit => bail!("config file {} not found", path.display()),
+        None => {}
+      }
+    }
+
+    if let Some(transport) = self.transport {
+      config.transport = transport;
+    }
+    if let Some(bus) = self.bus {
+      config.bus = bus;
+    }
+    if let Some(instance) = &self.instance {
+      config.instance = instance.clone();
+    }
+    if !self.modes.is_empty() {
+      config.modes.clone_from(&self.modes);
+    }
+    if self.fallback {
+      config.fallback = true;
+    }
+    if let Some(category) = self.category {
+      config.category = Some(category);
+    }
+    if let Some(what) = &self.logind_what {
+      config.logind_what = Some(what.clone());
+    }
+    if let Some(flags) = &self.portal_flags {
+      config.portal_flags = Some(flags.clone());
+    }
+    if let Some(app_id) = &self.app_id {
+      config.app_id.clone_from(app_id);
+    }
+    if let Some(interval) = self.xscreensaver_interval {
+      config.xscreensaver_interval = interval;
+    }
+    if let Some(interval) = self.mouse_jitter_interval {
+      config.mouse_jitter_interval = interval;
+    }
+    if let Some(interval) = self.reassert_interval {
+      config.reassert_interval = Some(interval);
+    }
+    if let Some(timeout) = self.idle_timeout {
+      config.idle_timeout = Some(timeout);
+    }
+    if let Some(cap) = self.max_extend {
+      config.max_extend = Some(cap);
+    }
+    if let Some(cap) = self.max_duration {
+      config.max_duration = Some(cap);
+    }
+    if let Some(before) = self.warn_before {
+      config.warn_before = Some(before);
+    }
+    if let Some(urgency) = self.notify_urgency {
+      config.notify_urgency = urgency;
+    }
+    if let Some(summary) = &self.notify_summary {
+      config.notify_summary = Some(summary.clone());
+    }
+    if let Some(body) = &self.notify_body {
+      config.notify_body = Some(body.clone());
+    }
+    if self.notify_active {
+      config.notify_active = true;
+    }
+    if let Some(interval) = self.watch_interval {
+      config.watch_interval = interval;
+    }
+    if let Some(delay) = self.startup_delay {
+      config.startup_delay = Some(delay);
+    }
+    if !self.presets.is_empty() {
+      config.presets.clone_from(&self.presets);
+    }
+    if self.release_on_lock {
+      config.release_on_lock = true;
+    }
+    if self.keep_alive {
+      config.keep_alive = true;
+    }
+    if let Some(addr) = self.metrics_addr {
+      config.metrics_addr = Some(addr);
+    }
+    if self.repeat {
+      config.repeat = true;
+    }
+    if let Some(name) = &self.while_process {
+      config.while_process = Some(name.clone());
+    }
+    if let Some(command) = &self.while_command {
+      config.while_command = Some(command.clone());
+    }
+    if self.inhibit_network {
+      config.inhibit_network = true;
+    }
+    if let Some(threshold) = self.network_threshold {
+      config.network_threshold = threshold;
+    }
+    if let Some(quiet) = self.network_quiet {
+      config.network_quiet = quiet;
+    }
+    if let Some(hold) = self.guard_hold {
+      config.guard_hold = Some(hold);
+    }
+    if let Some(clock) = self.clock {
+      config.clock = clock;
+    }
+
+    Ok(config)
+  }
+}
@@ -0,0 +1,122 @@
+//! The optional TOML config file, for settings one would otherwise
+//! pass on every start:
+//!
+//! ```toml
+//! mode = "logind"
+//! xscreensaver_interval = "30s"
+//! mouse_jitter_interval = "1m"
+//! ```
+//!
+//! Flags given on the command line win over it.
+
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use serde::Deserialize;
+
+use crate::{config::Config, helper, inhibitor::InhibitMode};
+
+/// Where the config file is looked for when not given explicitly
+pub fn default_path() -> Option<PathBuf> {
+  let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) => PathBuf::from(dir),
+    None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+  };
+  Some(dir.join("vigilare").join("config.toml"))
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+  mode: Option<InhibitMode>,
+  // in the syntax of the flags, e.g. "30s"
+  xscreensaver_interval: Option<String>,
+  mouse_jitter_interval: Option<String>,
+}
+
+impl ConfigFile {
+  /// Read the config file, None if there is none
+  pub fn load(path: &Path) -> Result<Option<Self>> {
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => {
+        return Err(e)
+          .with_context(|| format!("failed to read {}", path.display()))
+      }
+    };
+
+    let file = toml_edit::de::from_str(&text)
+      .with_context(|| format!("invalid config file {}", path.display()))?;
+    Ok(Some(file))
+  }
+
+  /// Set what the file sets on `config`
+  pub fn apply(&self, config: &mut Config) -> Result<()> {
+    if let Some(mode) = self.mode {
+      config.modes = vec![mode];
+    }
+    if let Some(interval) = &self.xscreensaver_interval {
+      config.xscreensaver_interval =
+        duration("xscreensaver_interval", interval)?;
+    }
+    if let Some(interval) = &self.mouse_jitter_interval {
+      config.mouse_jitter_interval =
+        duration("mouse_jitter_interval", interval)?;
+    }
+    Ok(())
+  }
+}
+
+fn duration(key: &str, value: &str) -> Result<std::time::Duration> {
+  helper::parse_duration(value).map_err(|e| anyhow!("{key}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  fn parse(text: &str) -> Result<ConfigFile, toml_edit::de::Error> {
+    toml_edit::de::from_str(text)
+  }
+
+  #[test]
+  fn applies_what_the_file_sets() {
+    let file = parse(
+      r#"
+      mode = "logind"
+      xscreensaver_interval = "30s"
+      "#,
+    )
+    .unwrap();
+    let mut config = Config::default();
+    file.apply(&mut config).unwrap();
+    assert_eq!(config.modes, [InhibitMode::Logind]);
+    assert_eq!(config.xscreensaver_interval, Duration::from_secs(30));
+  }
+
+  #[test]
+  fn takes_the_mode_aliases() {
+    let file = parse(r#"mode = "mouse""#).unwrap();
+    assert_eq!(file.mode, Some(InhibitMode::MouseJitter));
+  }
+
+  #[test]
+  fn rejects_what_it_doesnt_know() {
+    assert!(parse(r#"mode = "nope""#).is_err());
+    assert!(parse(r#"idle_timeout = "5m""#).is_err());
+    assert!(parse("mode = 1").is_err());
+  }
+
+  #[test]
+  fn rejects_malformed_durations() {
+    let file = parse(r#"mouse_jitter_interval = "soon""#).unwrap();
+    let e = file.apply(&mut Config::default()).unwrap_err();
+    assert!(e.to_string().starts_with("mouse_jitter_interval: "), "{e}");
+  }
+}
@@ -1,209 +1,1556 @@
-use std::time::{Duration, Instant, SystemTime};
+use std::{
+  future::Future,
+  ops::ControlFlow,
+  path::PathBuf,
+  pin::Pin,
+  sync::Mutex,
+  time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context as _, Result};
+use duration_string::DurationString;
+use futures::FutureExt as _;
 
-use tokio::sync::{mpsc, oneshot};
-use tracing::info;
-use zbus::object_server::InterfaceRef;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, error, info, warn};
+use zbus::object_server::{InterfaceRef, SignalContext};
 
 use crate::{
+  config::{Clock, Config},
+  detach::Detached,
+  guard, helper, idle,
   inhibitor::{self, InhibitMode, Inhibitor},
-  protocol::{DurationUpdate, Status},
-  signals,
+  metrics::{self, Metrics},
+  notify,
+  protocol::{
+    Bus, Connections, DurationUpdate, Status, Transport, UpdateOptions,
+  },
+  session::SessionType,
+  socket,
+  state::{self, State},
+  template::{self, Field, Template},
 };
 
 pub struct Daemon {
-  // None: computer is free to sleep
+  // kept to re-create the inhibitor after a resume
+  config: Config,
+  // shared by the service and the inhibitors
+  connections: Connections,
+  // whether the modes were switched to at runtime, and so are to be
+  // kept across restarts rather than taken from the flags again
+  modes_switched: bool,
+  // as given by the flags, the environment or the config file
+  configured_modes: Vec<InhibitMode>,
+  // where to keep that and the vigil, None to keep nothing
+  state_path: Option<PathBuf>,
+  transport: Transport,
+  bus: Bus,
+  // None: computer is free to sleep, unless the vigil is indefinite
   wake_until: Option<Instant>,
+  clock: Clock,
+  // both clocks as last read for --clock realtime, to tell how far
+  // the wall clock moved apart from the monotonic one since
+  clock_anchor: Option<(Instant, SystemTime)>,
+  // inhibiting with no deadline at all
+  indefinite: bool,
+  // when the current vigil began, kept across extensions
+  started_at: Option<SystemTime>,
+  // when the last vigil ran out, until the next one starts
+  expired_at: Option<SystemTime>,
+  // the length a rolling vigil restarts with on user activity
+  rolling: Option<Duration>,
+  user_idle: bool,
+  // who asked for the current vigil
+  label: Option<String>,
   inhibitor: Box<dyn Inhibitor>,
+  // the state last applied to the inhibitor
+  inhibited: bool,
+  // for the metrics: since when it's been held, how long it was held
+  // before and how often inhibiting failed
+  inhibited_since: Option<Instant>,
+  inhibited_total: Duration,
+  inhibit_failures: u64,
+  // exit once the vigil runs out
+  exit_on_deadline: bool,
+  repeat: bool,
+  // the length the current vigil was first given, for `repeat`
+  armed_for: Option<Duration>,
+  reassert_interval: Option<Duration>,
+  reassert_at: Option<Instant>,
+  idle_timeout: Option<Duration>,
+  // caps on a single extension and on the remaining time
+  max_extend: Option<Duration>,
+  max_duration: Option<Duration>,
+  warn_before: Option<Duration>,
+  // the deadline last warned about, so that each is warned about once
+  warned_for: Option<Instant>,
+  // the notification kept up during the vigil, for --notify-active
+  notification_id: Option<u32>,
+  watch_interval: Duration,
+  while_process: Option<String>,
+  // the activity guards currently wanting the machine awake
+  process_running: bool,
+  network_active: bool,
+  predicate_holds: bool,
+  // how long to stay awake after they all let go, and until when
+  guard_hold: Option<Duration>,
+  guard_held_until: Option<Instant>,
+  release_on_lock: bool,
+  // when the session got locked, while it is
+  locked_at: Option<Instant>,
+  // set when running in the background
+  detached: Option<Detached>,
+  // the D-Bus interface, when serving over D-Bus
+  iface: Option<InterfaceRef<DbusService>>,
+  // ticks on every status change, for socket subscribers
+  changes: watch::Sender<()>,
+}
+
+// Event sources owned by `run`, kept apart from the daemon state so
+// that they can be polled while the state is borrowed
+struct EventSources {
+  // None once the service that sent on it went away
+  receiver: Option<mpsc::Receiver<DaemonMessage>>,
+  // completes when the daemon should exit
+  shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+  idle: Option<mpsc::Receiver<bool>>,
+  process: Option<mpsc::Receiver<bool>>,
+  predicate: Option<mpsc::Receiver<bool>>,
+  network: Option<mpsc::Receiver<bool>>,
+  resume: Option<mpsc::Receiver<()>>,
+  lock: Option<mpsc::Receiver<bool>>,
 }
 
 enum DaemonEvent {
-  DurationUpdate(DurationUpdate),
+  DurationUpdate(DurationUpdate, UpdateOptions, UpdateReply),
+  ExtendByFactor(f64, UpdateReply),
+  SetMode(InhibitMode, UpdateReply),
+  Cancel(String, UpdateReply),
   StatusRequest(oneshot::Sender<Status>),
+  MetricsRequest(oneshot::Sender<Metrics>),
   Deadline,
+  // the vigil is about to end
+  Warn,
+  Reassert,
+  // true when the user went idle, false when they are back
+  Idle(bool),
+  // whether the --while-process process is running
+  ProcessRunning(bool),
+  // whether the --while-command command succeeds
+  PredicateResult(bool),
+  // whether a transfer is going on, for --inhibit-network
+  NetworkActive(bool),
+  // the guards let go long enough ago, see --guard-hold
+  GuardHoldOver,
+  // time to see whether the wall clock jumped, for --clock realtime
+  WallClockCheck,
+  Resumed,
+  // true when the session got locked, false when unlocked
+  Locked(bool),
+  InhibitorStopped(anyhow::Error),
   ExitSignal,
   DbusServiceExit,
 }
 
 impl Daemon {
-  pub async fn new(mode: InhibitMode) -> Result<Self> {
-    let inhibitor = inhibitor::from_mode(mode)
+  /// Set up a daemon talking to the buses through `connections`, e.g.
+  /// `Connections::retrying()`
+  pub async fn new(config: &Config, connections: Connections) -> Result<Self> {
+    // before anything, inhibitors may connect to the bus too
+    if let Some(delay) = config.startup_delay {
+      info!("Waiting {} before starting", fmt_duration(delay));
+      tokio::time::sleep(delay).await;
+    }
+
+    let configured_modes = config.modes.clone();
+    let (mut config, state) = restore_state(config);
+    config.modes = inhibitor::resolve_auto(&config.modes, &config).await?;
+    let config = &config;
+    let session = SessionType::detect();
+    for mode in &config.modes {
+      info!("Using inhibit mode {mode}");
+      if !mode.works_in(session) {
+        warn!("Mode {mode} has no effect in this session ({session})");
+      }
+    }
+
+    // the bus we serve on comes first, so that failing to reach it
+    // isn't mistaken for a problem with an inhibitor
+    if config.transport == Transport::Dbus {
+      let _conn = connections.get(config.bus).await?;
+    }
+
+    let inhibitor = inhibitor::from_modes(&config.modes, config, &connections)
       .await
-      .expect("Failed to create inhibitor");
+      .context("failed to set up the inhibitor")?;
 
-    Ok(Self {
+    let mut daemon = Self::with_inhibitor(config, connections, inhibitor);
+    daemon.modes_switched = !state.modes.is_empty();
+    daemon.configured_modes = configured_modes;
+    daemon.state_path = Some(state::default_path(&config.instance));
+    daemon.restore_vigil(&state);
+    Ok(daemon)
+  }
+
+  // A daemon holding `inhibitor`, with nothing picked up from the last
+  // run and nothing kept for the next
+  fn with_inhibitor(
+    config: &Config,
+    connections: Connections,
+    inhibitor: Box<dyn Inhibitor>,
+  ) -> Self {
+    Self {
+      config: config.clone(),
+      connections,
+      modes_switched: false,
+      configured_modes: config.modes.clone(),
+      state_path: None,
+      transport: config.transport,
+      bus: config.bus,
       wake_until: None,
+      clock: config.clock,
+      clock_anchor: None,
+      indefinite: false,
+      started_at: None,
+      expired_at: None,
+      rolling: None,
+      user_idle: false,
+      label: None,
       inhibitor,
-    })
+      inhibited: false,
+      inhibited_since: None,
+      inhibited_total: Duration::ZERO,
+      inhibit_failures: 0,
+      exit_on_deadline: false,
+      repeat: config.repeat,
+      armed_for: None,
+      reassert_interval: config.reassert_interval,
+      reassert_at: None,
+      idle_timeout: config.idle_timeout,
+      max_extend: config.max_extend,
+      max_duration: config.max_duration,
+      warn_before: config.warn_before,
+      warned_for: None,
+      notification_id: None,
+      watch_interval: config.watch_interval,
+      while_process: config.while_process.clone(),
+      process_running: false,
+      network_active: false,
+      predicate_holds: false,
+      guard_hold: config.guard_hold,
+      guard_held_until: None,
+      release_on_lock: config.release_on_lock,
+      locked_at: None,
+      detached: None,
+      iface: None,
+      changes: watch::Sender::new(()),
+    }
+  }
+
+  // Pick up the vigil the last run left off with, e.g. before the unit
+  // was restarted, unless it has ended since
+  fn restore_vigil(&mut self, state: &State) {
+    if state.indefinite {
+      info!("Restoring the indefinite vigil from the last run");
+      self.start_indefinite();
+      return;
+    }
+    if state.wake_until == 0 {
+      return;
+    }
+
+    let wake_until =
+      SystemTime::UNIX_EPOCH + Duration::from_secs(state.wake_until);
+    // the epoch is in whole seconds, so is what's left
+    let left = match wake_until.duration_since(SystemTime::now()) {
+      Ok(left) if left.as_secs() > 0 => Duration::from_secs(left.as_secs()),
+      _ => {
+        info!("The vigil of the last run has ended since");
+        return;
+      }
+    };
+    info!(
+      "Restoring the vigil from the last run, {} left",
+      fmt_duration(left)
+    );
+    if let Err(e) = self.start_vigil(left) {
+      warn!("Failed to restore the vigil: {e:#}");
+    }
   }
 
-  async fn get_event(
-    receiver: &mut mpsc::Receiver<DaemonMessage>,
-    deadline: &Option<Instant>,
-    exit_signals: &mut signals::ExitSignals,
-  ) -> DaemonEvent {
-    let sleep = deadline
-      .map(|d| tokio::time::sleep_until(d.into()))
-      .unwrap_or_else(|| tokio::time::sleep(Duration::MAX));
+  /// Start with a vigil of the given duration
+  pub fn start_vigil(&mut self, duration: Duration) -> Result<()> {
+    self.update_duration(DurationUpdate::Set(duration), &Default::default())
+  }
+
+  /// Start with a vigil of the given duration and exit when it ends.
+  /// It replaces any vigil picked up from the last run, and isn't
+  /// kept for the next, which would hold it without being asked to.
+  pub fn once(&mut self, duration: Duration) -> Result<()> {
+    self.state_path = None;
+    self.clear_vigil();
+    self.start_vigil(duration)?;
+    self.exit_on_deadline = true;
+    Ok(())
+  }
+
+  /// Signal the process that detached us once the service is up
+  pub fn detached(&mut self, detached: Detached) {
+    self.detached = Some(detached);
+  }
+
+  /// Start with a vigil that has no deadline
+  pub fn start_indefinite(&mut self) {
+    self.clear_vigil();
+    self.indefinite = true;
+    self.started_at = Some(SystemTime::now());
+    self.expired_at = None;
+  }
+
+  // a guard can hold the inhibitor without there being a vigil
+  fn keep_awake(&self) -> bool {
+    let guarded = self.guarded() || self.guard_held_until.is_some();
+    (self.vigil_active() || guarded) && self.locked_at.is_none()
+  }
+
+  // whether an activity guard wants the machine awake right now
+  fn guarded(&self) -> bool {
+    self.process_running || self.network_active || self.predicate_holds
+  }
+
+  // The clock vigils count down on, which stands still while the
+  // session is locked: the time a vigil has left is measured from
+  // here, and set from here by updates made while locked, and
+  // unlocking moves the deadline on by as long as the lock lasted
+  fn vigil_now(&self) -> Instant {
+    self.locked_at.unwrap_or_else(Instant::now)
+  }
+
+  // the countdown stands still while the session is locked
+  fn deadline(&self) -> Option<Instant> {
+    self.wake_until.filter(|_| self.locked_at.is_none())
+  }
+
+  // when to warn about the deadline coming up, right away when it's
+  // already closer than --warn-before
+  fn warn_at(&self) -> Option<Instant> {
+    let before = self.warn_before?;
+    let deadline = self.deadline()?;
+    if self.warned_for == Some(deadline) {
+      return None;
+    }
+    Some(deadline.checked_sub(before).unwrap_or(deadline))
+  }
+
+  fn vigil_active(&self) -> bool {
+    self.indefinite || self.wake_until.is_some()
+  }
+
+  async fn get_event(&mut self, sources: &mut EventSources) -> DaemonEvent {
+    let realtime = self.clock == Clock::Realtime;
+    if realtime {
+      self.follow_wall_clock();
+    }
 
     tokio::select! {
-      _ = exit_signals.recv() => {
+      _ = &mut sources.shutdown => {
         DaemonEvent::ExitSignal
       }
 
-      msg = receiver.recv() => {
+      msg = recv_some(&mut sources.receiver) => {
         match msg {
-          Some(DaemonMessage::DurationUpdate(update)) => {
-            DaemonEvent::DurationUpdate(update)
+          Some(DaemonMessage::DurationUpdate(update, options, reply)) => {
+            DaemonEvent::DurationUpdate(update, options, reply)
+          }
+          Some(DaemonMessage::ExtendByFactor(factor, reply)) => {
+            DaemonEvent::ExtendByFactor(factor, reply)
+          }
+          Some(DaemonMessage::SetMode(mode, reply)) => {
+            DaemonEvent::SetMode(mode, reply)
+          }
+          Some(DaemonMessage::Cancel(name, reply)) => {
+            DaemonEvent::Cancel(name, reply)
           }
           Some(DaemonMessage::StatusRequest(sender)) => {
             DaemonEvent::StatusRequest(sender)
           }
+          Some(DaemonMessage::MetricsRequest(sender)) => {
+            DaemonEvent::MetricsRequest(sender)
+          }
           None => {
+            sources.receiver = None;
             DaemonEvent::DbusServiceExit
           }
         }
       }
-      _ = sleep => {
+      Some(idle) = recv_some(&mut sources.idle) => {
+        DaemonEvent::Idle(idle)
+      }
+      Some(running) = recv_some(&mut sources.process) => {
+        DaemonEvent::ProcessRunning(running)
+      }
+      Some(holds) = recv_some(&mut sources.predicate) => {
+        DaemonEvent::PredicateResult(holds)
+      }
+      Some(active) = recv_some(&mut sources.network) => {
+        DaemonEvent::NetworkActive(active)
+      }
+      Some(()) = recv_some(&mut sources.resume) => {
+        DaemonEvent::Resumed
+      }
+      Some(locked) = recv_some(&mut sources.lock) => {
+        DaemonEvent::Locked(locked)
+      }
+      _ = sleep_until(self.deadline()) => {
         DaemonEvent::Deadline
       }
+      _ = sleep_until(self.warn_at()) => {
+        DaemonEvent::Warn
+      }
+      _ = sleep_until(self.reassert_at) => {
+        DaemonEvent::Reassert
+      }
+      _ = sleep_until(self.guard_held_until) => {
+        DaemonEvent::GuardHoldOver
+      }
+      // the timers above run on the monotonic clock, look at the wall
+      // clock again every so often, e.g. for after a suspend
+      _ = tokio::time::sleep(WALL_CLOCK_CHECK), if realtime => {
+        DaemonEvent::WallClockCheck
+      }
+      e = self.inhibitor.stopped() => {
+        DaemonEvent::InhibitorStopped(e)
+      }
     }
   }
 
-  pub async fn run(&mut self) -> Result<()> {
-    let (sender, mut receiver) = mpsc::channel(1);
-    let dbus_service = DbusService { sender };
-    let conn = zbus::connection::Builder::session()?
-      .name("org.shou.Vigilare")?
-      .serve_at("/org/shou/Vigilare", dbus_service)?
-      .build()
-      .await?;
+  /// Serve until `shutdown` completes, e.g. on `signals::exit_signal`.
+  /// Signal handling is left to the caller, so that an application
+  /// embedding the daemon keeps control over its own lifecycle.
+  pub async fn run<F>(&mut self, shutdown: F) -> Result<()>
+  where
+    F: Future + Send + 'static,
+  {
+    let (sender, receiver) = mpsc::channel(1);
+    let can_roll = self.idle_timeout.is_some();
+
+    let _metrics = match self.config.metrics_addr {
+      Some(addr) => {
+        let listener = metrics::bind(addr).await?;
+        info!("Serving metrics on http://{addr}/metrics");
+        let sender = sender.downgrade();
+        let task = tokio::spawn(metrics::serve(listener, sender));
+        Some(AbortOnDrop(task))
+      }
+      None => None,
+    };
+
+    // held until we exit, dropping either stops the service
+    let (_conn, _server) = match self.transport {
+      Transport::Dbus => {
+        let conn = self.serve_dbus(sender, can_roll).await?;
+        match conn.unique_name() {
+          Some(name) => info!("Daemon started at {name}"),
+          None => info!("Daemon started on a private connection"),
+        }
+        self.mode_changed().await;
+        (Some(conn), None)
+      }
+      Transport::Socket => {
+        let path = socket::default_path(&self.config.instance);
+        let listener = socket::Listener::bind(&path).await?;
+        info!("Daemon listening on {}", listener.path().display());
+        let server = socket::Server {
+          sender,
+          changes: self.changes.subscribe(),
+          can_roll,
+          presets: self.config.presets.clone(),
+        };
+        let task = tokio::spawn(async move { server.serve(&listener).await });
+        (None, Some(AbortOnDrop(task)))
+      }
+    };
+
+    let idle = match self.idle_timeout {
+      Some(timeout) => Some(idle::watch(timeout)?),
+      None => None,
+    };
 
-    let iface: InterfaceRef<DbusService> =
-      conn.object_server().interface("/org/shou/Vigilare").await?;
+    // not every system has logind, resuming is then simply not noticed
+    let resume = inhibitor::watch_resume(&self.connections)
+      .await
+      .map_err(|e| debug!("Not watching for resume: {e:#}"))
+      .ok();
 
-    let status_changed = || async {
-      let signal_ctx = iface.signal_context();
-      let iface = iface.get().await;
-      iface
-        .status_invalidate(signal_ctx)
+    let lock = if self.release_on_lock {
+      inhibitor::watch_lock(&self.connections)
         .await
-        .expect("Failed to emit status changed");
+        .map_err(|e| warn!("Not watching for the screen lock: {e:#}"))
+        .ok()
+    } else {
+      None
     };
 
-    let mut exit_signals = signals::ExitSignals::new();
+    // checked right away rather than on the first tick, for when the
+    // daemon is started by the very process it guards
+    let process = self.while_process.clone().map(|name| {
+      self.process_running = guard::process_running(&name);
+      guard::poll(self.watch_interval, self.process_running, move || {
+        guard::process_running(&name)
+      })
+    });
 
-    info!(
-      "Daemon started at {}",
-      conn.unique_name().expect("Failed to get unique name")
-    );
-    status_changed().await;
+    // run on a thread of its own, the first time too, so a slow
+    // command doesn't hold up the daemon, only the next check
+    let predicate = match self.config.while_command.clone() {
+      Some(command) => {
+        let (holds, changes) =
+          guard::poll_checked(self.watch_interval, move || {
+            guard::command_succeeds(&command)
+          })
+          .await;
+        self.predicate_holds = holds;
+        Some(changes)
+      }
+      None => None,
+    };
 
-    loop {
-      let event =
-        Self::get_event(&mut receiver, &self.wake_until, &mut exit_signals);
+    let network = self.config.inhibit_network.then(|| {
+      let mut activity = guard::NetworkActivity::new(
+        self.config.network_threshold,
+        self.config.network_quiet,
+      );
+      guard::poll(self.watch_interval, false, move || activity.check())
+    });
 
-      match event.await {
-        DaemonEvent::DurationUpdate(update) => {
-          self.update_duration(update)?;
-          self.update_inhibitor().await?;
-          status_changed().await;
-        }
-        DaemonEvent::StatusRequest(sender) => {
-          sender.send(self.status()).ok();
+    let mut sources = EventSources {
+      receiver: Some(receiver),
+      // fused, as it's polled again by whatever select comes next
+      shutdown: Box::pin(shutdown.map(|_| ()).fuse()),
+      idle,
+      process,
+      predicate,
+      network,
+      resume,
+      lock,
+    };
+
+    if let Some(detached) = &mut self.detached {
+      detached.ready()?;
+    }
+    if self.keep_awake() {
+      tokio::select! {
+        _ = self.follow_inhibitor() => {}
+        _ = &mut sources.shutdown => {
+          info!("Received exit signal, exiting");
+          return Ok(());
         }
-        DaemonEvent::Deadline => {
-          self.wake_until = None;
-          self.update_inhibitor().await?;
-          status_changed().await;
+      }
+    }
+    self.status_changed().await;
+
+    loop {
+      let event = self.get_event(&mut sources).await;
+
+      // inhibitor calls can be slow, keep listening for exit signals
+      // so that shutdown stays prompt
+      tokio::select! {
+        flow = self.handle_event(event) => {
+          if flow?.is_break() {
+            break;
+          }
         }
-        DaemonEvent::ExitSignal => {
+        _ = &mut sources.shutdown => {
           info!("Received exit signal, exiting");
           break;
         }
-        DaemonEvent::DbusServiceExit => {
-          info!("Dbus service exited");
-          break;
+      }
+    }
+
+    if let Some(id) = self.notification_id {
+      if let Err(e) = self.close_notification(id).await {
+        warn!("Failed to close the notification: {e:#}");
+      }
+    }
+    Ok(())
+  }
+
+  async fn handle_event(
+    &mut self,
+    event: DaemonEvent,
+  ) -> Result<ControlFlow<()>> {
+    match event {
+      DaemonEvent::DurationUpdate(update, options, reply) => {
+        if !options.label.is_empty() {
+          info!("Update {update} from {}", options.label);
         }
+        self.update_duration(update, &options)?;
+        return Ok(self.updated(reply).await);
+      }
+      DaemonEvent::ExtendByFactor(factor, reply) => {
+        let Some(wake_until) = self.wake_until else {
+          debug!("No deadline to extend by {factor}");
+          reply.send(Ok(())).ok();
+          return Ok(ControlFlow::Continue(()));
+        };
+
+        let remaining = wake_until.saturating_duration_since(self.vigil_now());
+        let scaled =
+          Duration::try_from_secs_f64(remaining.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX);
+        info!("Extending the vigil by {factor}x");
+        self
+          .update_duration(DurationUpdate::Set(scaled), &Default::default())?;
+        return Ok(self.updated(reply).await);
+      }
+      DaemonEvent::Cancel(name, reply) => {
+        // the vigil is the only timer there is, named by its label
+        if !self.vigil_active() || self.label.as_deref() != Some(&name) {
+          reply.send(Err(format!("no timer named {name}"))).ok();
+          return Ok(ControlFlow::Continue(()));
+        }
+        info!("Cancelling {name}");
+        self.update_duration(
+          DurationUpdate::Set(Duration::ZERO),
+          &Default::default(),
+        )?;
+        return Ok(self.updated(reply).await);
+      }
+      DaemonEvent::StatusRequest(sender) => {
+        sender.send(self.status()).ok();
+      }
+      DaemonEvent::MetricsRequest(sender) => {
+        sender.send(self.metrics()).ok();
       }
+      DaemonEvent::Deadline => {
+        if let (Some(window), false) = (self.rolling, self.user_idle) {
+          debug!("User still active, restarting rolling vigil");
+          self.wake_until = Some(Instant::now() + window);
+          self.status_changed().await;
+          return Ok(ControlFlow::Continue(()));
+        }
+
+        let repeat = self.armed_for.filter(|_| self.repeat);
+        let label = self.label.clone().unwrap_or_default();
+        self.clear_vigil();
+        self.follow_inhibitor().await;
+
+        if let Some(length) = repeat {
+          info!("Vigil ended, repeating it for {}", fmt_duration(length));
+          let options = UpdateOptions {
+            label,
+            ..Default::default()
+          };
+          self.update_duration(DurationUpdate::Set(length), &options)?;
+          self.follow_inhibitor().await;
+          self.status_changed().await;
+          return Ok(ControlFlow::Continue(()));
+        }
+        self.expired_at = Some(SystemTime::now());
+        self.status_changed().await;
+
+        if self.exit_on_deadline {
+          info!("Vigil ended, exiting");
+          return Ok(ControlFlow::Break(()));
+        }
+      }
+      DaemonEvent::Warn => {
+        self.warned_for = self.deadline();
+        self.warn_expiry().await;
+      }
+      DaemonEvent::Reassert => {
+        self.reassert_at = self.reassert_interval.map(|i| Instant::now() + i);
+        // a change the inhibitor failed to follow is tried again first
+        if self.keep_awake() != self.inhibited {
+          self.follow_inhibitor().await;
+          return Ok(ControlFlow::Continue(()));
+        }
+
+        info!("Re-asserting inhibitor");
+        let degraded = self.inhibitor.degraded();
+        if let Err(e) = self.inhibitor.refresh().await {
+          // e.g. the peer is restarting, so try again on the next tick
+          error!("Failed to re-assert the inhibitor: {e:#}");
+          self.inhibit_failures += 1;
+        }
+        // a mode that failed before may have been taken up again
+        if self.inhibitor.degraded() != degraded {
+          self.status_changed().await;
+        }
+      }
+      DaemonEvent::Idle(idle) => {
+        if idle {
+          debug!("User went idle");
+        } else {
+          debug!("User is active again");
+        }
+        self.user_idle = idle;
+
+        if self.rolling.is_some() {
+          self.roll();
+          self.follow_inhibitor().await;
+          self.status_changed().await;
+        }
+      }
+      DaemonEvent::ProcessRunning(running) => {
+        let name = self.while_process.as_deref().unwrap_or_default();
+        if running {
+          info!("Process {name} is running, staying awake");
+        } else {
+          info!("Process {name} is gone");
+        }
+        let was_guarded = self.guarded();
+        self.process_running = running;
+        self.guard_changed(was_guarded).await;
+      }
+      DaemonEvent::PredicateResult(holds) => {
+        if holds {
+          info!("Command succeeds, staying awake");
+        } else {
+          info!("Command fails");
+        }
+        let was_guarded = self.guarded();
+        self.predicate_holds = holds;
+        self.guard_changed(was_guarded).await;
+      }
+      DaemonEvent::NetworkActive(active) => {
+        if active {
+          info!("Network transfer going on, staying awake");
+        } else {
+          info!("Network quiet");
+        }
+        let was_guarded = self.guarded();
+        self.network_active = active;
+        self.guard_changed(was_guarded).await;
+      }
+      // the checking is done before waiting for the next event
+      DaemonEvent::WallClockCheck => {}
+      DaemonEvent::GuardHoldOver => {
+        info!("Guard hold over");
+        self.guard_held_until = None;
+        self.follow_inhibitor().await;
+      }
+      DaemonEvent::SetMode(mode, reply) => {
+        let modes = match inhibitor::resolve_auto(&[mode], &self.config).await {
+          Ok(modes) => modes,
+          Err(e) => {
+            reply.send(Err(format!("{e:#}"))).ok();
+            return Ok(ControlFlow::Continue(()));
+          }
+        };
+        info!("Switching to inhibit mode {}", modes[0]);
+        let previous = std::mem::replace(&mut self.config.modes, modes);
+        let result = self.recreate_inhibitor().await;
+        if result.is_ok() {
+          self.modes_switched = true;
+          self.save_state();
+          self.mode_changed().await;
+          if self.config.notify_active {
+            self.update_notification().await;
+          }
+        } else {
+          self.config.modes = previous;
+        }
+        reply.send(result.map_err(|e| format!("{e:#}"))).ok();
+      }
+      DaemonEvent::Resumed => {
+        info!("Resumed from sleep, re-creating the inhibitor");
+        if let Err(e) = self.recreate_inhibitor().await {
+          error!("Failed to re-create inhibitor: {e:#}");
+        }
+      }
+      DaemonEvent::Locked(locked) => {
+        if locked == self.locked_at.is_some() {
+          return Ok(ControlFlow::Continue(()));
+        }
+
+        if locked {
+          info!("Session locked, releasing the inhibitor");
+          self.locked_at = Some(Instant::now());
+        } else {
+          info!("Session unlocked");
+          // the vigil picks up with the time it had left
+          if let (Some(at), Some(wake_until)) =
+            (self.locked_at.take(), self.wake_until)
+          {
+            self.wake_until = Some(wake_until + at.elapsed());
+          }
+        }
+        self.follow_inhibitor().await;
+        self.status_changed().await;
+      }
+      DaemonEvent::InhibitorStopped(e) => {
+        warn!("Inhibitor stopped: {e:#}, restarting it");
+        if let Err(e) = self.inhibitor.inhibit().await {
+          // retried on the next update
+          error!("Failed to restart inhibitor: {e:#}");
+          self.inhibit_failures += 1;
+          self.set_inhibited(false);
+        }
+      }
+      DaemonEvent::ExitSignal => {
+        info!("Received exit signal, exiting");
+        return Ok(ControlFlow::Break(()));
+      }
+      DaemonEvent::DbusServiceExit if self.config.keep_alive => {
+        warn!("Dbus service exited, carrying on without it");
+        // nothing is left to emit signals on
+        self.iface = None;
+      }
+      DaemonEvent::DbusServiceExit => {
+        info!("Dbus service exited");
+        return Ok(ControlFlow::Break(()));
+      }
+    }
+
+    Ok(ControlFlow::Continue(()))
+  }
+
+  // Apply a changed vigil and answer the client that changed it
+  async fn updated(&mut self, reply: UpdateReply) -> ControlFlow<()> {
+    // the client hears about a failing inhibitor instead of the
+    // daemon going down; the next update tries again
+    let result = self.update_inhibitor().await.map_err(|e| {
+      error!("Failed to update inhibitor: {e:#}");
+      format!("vigil updated but inhibitor failed: {e:#}")
+    });
+    reply.send(result).ok();
+    self.status_changed().await;
+
+    if self.exit_on_deadline && !self.vigil_active() {
+      info!("Vigil cleared, exiting");
+      return ControlFlow::Break(());
     }
+    ControlFlow::Continue(())
+  }
+
+  // Build the inhibitor for the configured modes anew and move the
+  // inhibition over to it, for after a resume, where peers on the bus
+  // may have restarted and taken our inhibitions and proxies with
+  // them, and for switching modes. Fails only if the new one can't be
+  // built, leaving the old one in place.
+  async fn recreate_inhibitor(&mut self) -> Result<()> {
+    let inhibitor = inhibitor::from_modes(
+      &self.config.modes,
+      &self.config,
+      &self.connections,
+    )
+    .await?;
 
+    if self.inhibited {
+      if let Err(e) = self.inhibitor.uninhibit().await {
+        debug!("Failed to release the old inhibitor: {e:#}");
+      }
+    }
+    self.inhibitor = inhibitor;
+    self.set_inhibited(false);
+
+    if let Err(e) = self.update_inhibitor().await {
+      // retried on the next update
+      error!("Failed to re-inhibit: {e:#}");
+    }
     Ok(())
   }
 
-  fn update_duration(&mut self, update: DurationUpdate) -> Result<()> {
-    let now = Instant::now();
+  // keep what a restart should pick up again
+  fn save_state(&self) {
+    let Some(path) = &self.state_path else {
+      return;
+    };
+    let mut state = State::default();
+    if self.modes_switched {
+      state.modes.clone_from(&self.config.modes);
+      state.configured.clone_from(&self.configured_modes);
+    }
+    let status = self.status();
+    state.wake_until = status.wake_until;
+    state.indefinite = status.indefinite();
+    if let Err(e) = state.save(path) {
+      warn!("Failed to save the state: {e:#}");
+    }
+  }
+
+  fn update_duration(
+    &mut self,
+    update: DurationUpdate,
+    options: &UpdateOptions,
+  ) -> Result<()> {
+    let was_active = self.vigil_active();
+    if self.indefinite {
+      match update {
+        // there is no deadline to move
+        DurationUpdate::Add(_) | DurationUpdate::Sub(_) => {
+          debug!("Ignoring {update:?} on an indefinite vigil");
+          return Ok(());
+        }
+        DurationUpdate::Set(_) => self.indefinite = false,
+      }
+    }
+
+    let now = self.vigil_now();
     let wake_until = self.wake_until.unwrap_or(now);
+    let longest = now + LONGEST_VIGIL;
 
-    let new_wake_until = match update {
-      DurationUpdate::Add(duration) => wake_until + duration,
-      DurationUpdate::Sub(duration) => wake_until - duration,
-      DurationUpdate::Set(duration) => now + duration,
+    let mut new_wake_until = match update {
+      DurationUpdate::Add(duration) => wake_until
+        .checked_add(self.clamp_extend(duration))
+        .unwrap_or(longest),
+      // ends the vigil rather than underflowing
+      DurationUpdate::Sub(duration) => {
+        wake_until.checked_sub(duration).unwrap_or(now)
+      }
+      DurationUpdate::Set(duration) => {
+        now.checked_add(duration).unwrap_or(longest)
+      }
     };
 
+    if let Some(cap) = self.max_duration {
+      let cap_at = now.checked_add(cap).unwrap_or(longest);
+      if new_wake_until > cap_at {
+        warn!("Capping the vigil at --max-duration {}", fmt_duration(cap));
+        new_wake_until = cap_at;
+      }
+    }
+    // so that there's no overflowing it later on either
+    new_wake_until = new_wake_until.min(longest);
+
+    // a deadline of exactly now clears the vigil: that's both "0",
+    // i.e. `Set(ZERO)`, and a `Sub` past the deadline, which saturates
+    // to now. Anything later arms it, if only for a nanosecond.
     if new_wake_until <= now {
-      self.wake_until = None;
+      self.clear_vigil();
     } else {
+      if !was_active {
+        self.started_at = Some(SystemTime::now());
+        self.expired_at = None;
+        self.armed_for = Some(new_wake_until - now);
+      }
       self.wake_until = Some(new_wake_until);
+      if !options.label.is_empty() {
+        self.label = Some(options.label.clone());
+      }
+
+      // a rolling vigil restarts with whatever length it was given last
+      if options.rolling || self.rolling.is_some() {
+        self.rolling = Some(new_wake_until - now);
+      }
     }
 
     Ok(())
   }
 
+  async fn serve_dbus(
+    &mut self,
+    sender: mpsc::Sender<DaemonMessage>,
+    can_roll: bool,
+  ) -> Result<zbus::Connection> {
+    let dbus_service = DbusService {
+      sender,
+      deadline: Mutex::new((None, None, false)),
+      can_roll,
+      presets: self.config.presets.clone(),
+    };
+    let conn = self.connections.get(self.bus).await?;
+    let name = self.config.instance.service_name();
+    let path = self.config.instance.object_path();
+
+    // serve before claiming the name, so that whoever sees the name
+    // can call on it right away
+    let served = conn
+      .object_server()
+      .at(path.as_str(), dbus_service)
+      .await
+      .map_err(|e| service_error(e, &name, self.bus))?;
+    if !served {
+      bail!(
+        "{name} is already served at {path}; \
+         is another vigilare daemon running in this process?"
+      );
+    }
+    // a private connection has no bus to claim the name on
+    if conn.is_bus() {
+      conn
+        .request_name(name.as_str())
+        .await
+        .map_err(|e| service_error(e, &name, self.bus))?;
+    }
+
+    self.iface = Some(conn.object_server().interface(path.as_str()).await?);
+    Ok(conn)
+  }
+
+  async fn status_changed(&mut self) {
+    self.changes.send_replace(());
+    self.save_state();
+    if self.config.notify_active {
+      self.update_notification().await;
+    }
+
+    let Some(iface_ref) = &self.iface else {
+      return;
+    };
+    let signal_ctx = iface_ref.signal_context();
+    // a read lock, since method calls awaiting the daemon hold one too
+    let iface = iface_ref.get().await;
+    *iface.deadline.lock().unwrap() =
+      (self.wake_until, self.locked_at, self.indefinite);
+
+    let emitted = match iface.status_invalidate(signal_ctx).await {
+      Ok(()) => iface.remaining_seconds_changed(signal_ctx).await,
+      Err(e) => Err(e),
+    };
+    drop(iface);
+
+    if let Err(e) = emitted {
+      if !self.config.keep_alive {
+        panic!("Failed to emit status changed: {e}");
+      }
+      // most likely the bus went down
+      warn!("Lost the D-Bus service ({e}), carrying on without it");
+      self.iface = None;
+    }
+  }
+
+  // tell front-ends the mode in effect, apart from the status since
+  // it changes far less often
+  async fn mode_changed(&self) {
+    let Some(iface) = &self.iface else {
+      return;
+    };
+    let mode = self.mode_name();
+    if let Err(e) =
+      DbusService::mode_changed(iface.signal_context(), &mode).await
+    {
+      warn!("Failed to emit mode changed: {e}");
+    }
+  }
+
+  // the modes in effect, as `--mode` takes them
+  fn mode_name(&self) -> String {
+    let modes: Vec<_> = self.config.modes.iter().map(|m| m.as_str()).collect();
+    modes.join(",")
+  }
+
+  fn clamp_extend(&self, duration: Duration) -> Duration {
+    match self.max_extend {
+      Some(cap) if duration > cap => {
+        warn!(
+          "Extending by {} instead of {}, see --max-extend",
+          fmt_duration(cap),
+          fmt_duration(duration)
+        );
+        cap
+      }
+      _ => duration,
+    }
+  }
+
+  async fn warn_expiry(&mut self) {
+    if self.deadline().is_none() {
+      return;
+    }
+    info!(
+      "Vigil ends in {}",
+      self.notification_field(Field::Remaining)
+    );
+
+    if let Err(e) = self.notify(true).await {
+      warn!("Failed to warn about the vigil ending: {e:#}");
+    }
+  }
+
+  // Show, update or take down the notification kept up during a
+  // vigil, following the vigil
+  async fn update_notification(&mut self) {
+    let result = match (self.vigil_active(), self.notification_id) {
+      (true, _) => self.notify(false).await,
+      (false, Some(id)) => self.close_notification(id).await,
+      (false, None) => Ok(()),
+    };
+    if let Err(e) = result {
+      warn!("Failed to update the notification: {e:#}");
+    }
+  }
+
+  async fn close_notification(&mut self, id: u32) -> Result<()> {
+    self.notification_id = None;
+    let conn = self.connections.try_get(Bus::Session).await?;
+    notify::close(&conn, id).await
+  }
+
+  // Show a notification about the vigil, in place of the one kept up
+  // during it if there is one
+  async fn notify(&mut self, warning: bool) -> Result<()> {
+    let render = |template: &Option<Template>, default: &str| match template {
+      Some(template) => template.render(|f| self.notification_field(f)),
+      None => default.to_string(),
+    };
+    let left = self.notification_field(Field::Remaining);
+    let (summary, body) = match (warning, self.deadline()) {
+      (true, _) => (
+        "Vigil ending soon",
+        format!("The machine may go to sleep in {left}"),
+      ),
+      (false, Some(_)) => ("Staying awake", format!("for another {left}")),
+      (false, None) => ("Staying awake", "until the vigil is cleared".into()),
+    };
+    let summary = render(&self.config.notify_summary, summary);
+    let body = render(&self.config.notify_body, &body);
+
+    let notification = notify::Notification {
+      app_name: &self.config.app_id,
+      summary: &summary,
+      body: &body,
+      urgency: self.config.notify_urgency,
+      replaces_id: self.notification_id.unwrap_or(0),
+      persistent: self.config.notify_active,
+    };
+    let conn = self.connections.try_get(Bus::Session).await?;
+    let id = notify::send(&conn, &notification).await?;
+    if self.config.notify_active {
+      self.notification_id = Some(id);
+    }
+    Ok(())
+  }
+
+  fn notification_field(&self, field: Field) -> String {
+    // in whole seconds, rounded up so that it never reads zero
+    let left = self.deadline().map(|deadline| {
+      let left = deadline.saturating_duration_since(Instant::now());
+      (left.as_secs() + u64::from(left.subsec_nanos() > 0)).max(1)
+    });
+    match field {
+      Field::Remaining => match left {
+        Some(secs) => fmt_duration(Duration::from_secs(secs)).to_string(),
+        None if self.indefinite => "∞".to_string(),
+        None => String::new(),
+      },
+      Field::RemainingSeconds => {
+        left.map(|secs| secs.to_string()).unwrap_or_default()
+      }
+      Field::State => match (self.vigil_active(), left) {
+        (false, _) => "inactive",
+        (true, None) => "indefinite",
+        (true, Some(_)) => "active",
+      }
+      .to_string(),
+      Field::WakeUntilLocal => match self.status().wake_until {
+        0 => String::new(),
+        at => template::local_time(at),
+      },
+      Field::Label => self.label.clone().unwrap_or_default(),
+      Field::Mode => self.mode_name(),
+    }
+  }
+
+  fn clear_vigil(&mut self) {
+    self.wake_until = None;
+    self.indefinite = false;
+    self.started_at = None;
+    self.armed_for = None;
+    self.rolling = None;
+    self.label = None;
+  }
+
+  // Count a rolling vigil down from the user's last activity
+  fn roll(&mut self) {
+    let Some(window) = self.rolling else {
+      return;
+    };
+
+    let now = self.vigil_now();
+    let last_active = match (self.user_idle, self.idle_timeout) {
+      (true, Some(timeout)) => now.checked_sub(timeout).unwrap_or(now),
+      _ => now,
+    };
+
+    let deadline = last_active + window;
+    if deadline <= now {
+      self.clear_vigil();
+    } else {
+      self.wake_until = Some(deadline);
+    }
+  }
+
   async fn update_inhibitor(&mut self) -> Result<()> {
-    match self.wake_until {
-      None => {
-        info!("Uninhibiting");
-        self.inhibitor.uninhibit().await?
+    let inhibit = self.keep_awake();
+    if inhibit == self.inhibited {
+      return Ok(());
+    }
+
+    if inhibit {
+      match &self.label {
+        Some(label) => info!("Inhibiting for {label}"),
+        None => info!("Inhibiting"),
       }
-      Some(_wake_until) => {
-        info!("Inhibiting");
-        self.inhibitor.inhibit().await?
+      // a failed attempt is retried on the next --reassert-interval
+      // tick too
+      self.reassert_at = self.reassert_interval.map(|i| Instant::now() + i);
+      if let Err(e) = self.inhibitor.inhibit().await {
+        self.inhibit_failures += 1;
+        return Err(e);
       }
+    } else {
+      info!("Uninhibiting");
+      self.inhibitor.uninhibit().await?;
+      self.reassert_at = None;
     }
 
+    self.set_inhibited(inhibit);
     Ok(())
   }
 
+  // Like `update_inhibitor`, where there's no client to tell about a
+  // failure: it's logged, and the next event or reassert tick tries
+  // again rather than the daemon giving up
+  async fn follow_inhibitor(&mut self) {
+    if let Err(e) = self.update_inhibitor().await {
+      error!("Failed to update inhibitor: {e:#}");
+    }
+  }
+
+  // Move the deadline by however much the wall clock moved apart from
+  // the monotonic one, so that the vigil ends at the same wall clock
+  // time, whether the clock was set or the machine was suspended
+  fn follow_wall_clock(&mut self) {
+    let now = (Instant::now(), SystemTime::now());
+    // a paused vigil picks up where it was, see `Locked`
+    let (Some(wake_until), None) = (self.wake_until, self.locked_at) else {
+      self.clock_anchor = Some(now);
+      return;
+    };
+    let Some((mono, wall)) = self.clock_anchor else {
+      self.clock_anchor = Some(now);
+      return;
+    };
+
+    let mono_elapsed = now.0 - mono;
+    let new_wake_until = match now.1.duration_since(wall) {
+      Ok(wall_elapsed) if wall_elapsed >= mono_elapsed => {
+        let ahead = wall_elapsed - mono_elapsed;
+        wake_until.checked_sub(ahead).unwrap_or(now.0)
+      }
+      Ok(wall_elapsed) => wake_until + (mono_elapsed - wall_elapsed),
+      // the wall clock was set back
+      Err(e) => wake_until + mono_elapsed + e.duration(),
+    };
+
+    let moved = if new_wake_until > wake_until {
+      new_wake_until - wake_until
+    } else {
+      wake_until - new_wake_until
+    };
+    // the clocks drift apart a little all along, which is only
+    // followed once it adds up, as measured from the anchor
+    if moved <= CLOCK_SLACK {
+      return;
+    }
+
+    info!("Wall clock moved by {}, following it", fmt_duration(moved));
+    self.clock_anchor = Some(now);
+    self.wake_until = Some(new_wake_until);
+    // still the same vigil, to be warned about only once
+    if self.warned_for == Some(wake_until) {
+      self.warned_for = Some(new_wake_until);
+    }
+  }
+
+  // Hold on to an activity guard that just let go for --guard-hold,
+  // starting over whenever one wants the machine awake again
+  async fn guard_changed(&mut self, was_guarded: bool) {
+    if self.guarded() {
+      self.guard_held_until = None;
+    } else if let (true, Some(hold)) = (was_guarded, self.guard_hold) {
+      info!("Holding on for another {}", fmt_duration(hold));
+      self.guard_held_until = Some(Instant::now() + hold);
+    }
+    self.follow_inhibitor().await;
+  }
+
+  // keeps count of how long the inhibitor was held along the way
+  fn set_inhibited(&mut self, inhibited: bool) {
+    match (self.inhibited_since, inhibited) {
+      (None, true) => self.inhibited_since = Some(Instant::now()),
+      (Some(since), false) => {
+        self.inhibited_total += since.elapsed();
+        self.inhibited_since = None;
+      }
+      _ => {}
+    }
+    self.inhibited = inhibited;
+  }
+
+  fn metrics(&self) -> Metrics {
+    let held = self.inhibited_since.map(|since| since.elapsed());
+    Metrics {
+      active: self.vigil_active(),
+      remaining_seconds: self.wake_until.map(|at| {
+        // partial seconds count as whole ones, as in the client
+        let left = at.saturating_duration_since(self.vigil_now());
+        left.as_secs() + u64::from(left.subsec_nanos() > 0)
+      }),
+      inhibited: self.inhibited_total + held.unwrap_or_default(),
+      inhibit_failures: self.inhibit_failures,
+    }
+  }
+
   fn status(&self) -> Status {
-    if self.wake_until.is_none() {
+    let started_at = self.started_at.map_or(0, unix_epoch);
+    let label = self.label.clone().unwrap_or_default();
+    let last_expired_at = self.expired_at.map_or(0, unix_epoch);
+    let degraded = if self.inhibited {
+      self.inhibitor.degraded().unwrap_or_default()
+    } else {
+      String::new()
+    };
+    let Some(wake_until) = self.wake_until else {
       return Status {
         wake_until: 0,
-        active: false,
+        started_at,
+        active: self.indefinite,
+        label,
+        last_expired_at,
+        degraded,
       };
-    }
+    };
 
-    let now = Instant::now();
-    let wake_until = self.wake_until.unwrap_or(now);
-    let wake_after = wake_until.saturating_duration_since(now);
+    // frozen while the session is locked
+    let wake_after = wake_until.saturating_duration_since(self.vigil_now());
     let now_system = SystemTime::now();
     let wake_until_system = now_system + wake_after;
-    let unix_epoch = wake_until_system
-      .duration_since(SystemTime::UNIX_EPOCH)
-      .expect("Failed to convert to UNIX epoch time")
-      .as_secs();
 
     Status {
-      wake_until: unix_epoch,
+      wake_until: unix_epoch(wake_until_system),
+      started_at,
       active: true,
+      label,
+      last_expired_at,
+      degraded,
     }
   }
 }
 
+// The configuration with the modes the last run left behind applied,
+// and what else it left
+fn restore_state(config: &Config) -> (Config, State) {
+  let mut config = config.clone();
+  let mut state = match State::load(&state::default_path(&config.instance)) {
+    Ok(state) => state.unwrap_or_default(),
+    Err(e) => {
+      warn!("Ignoring the state of the last run: {e:#}");
+      State::default()
+    }
+  };
+  restore_modes(&mut config, &mut state);
+  (config, state)
+}
+
+// The modes switched to in the last run win over the configured ones,
+// unless those changed since, e.g. in the unit or the config file.
+// Dropped from `state` then, so that they're not saved again.
+fn restore_modes(config: &mut Config, state: &mut State) {
+  if state.modes.is_empty() {
+    return;
+  }
+
+  let names = |modes: &[InhibitMode]| {
+    let names: Vec<_> = modes.iter().map(|m| m.to_string()).collect();
+    names.join(", ")
+  };
+  if state.configured == config.modes {
+    info!(
+      "Restoring inhibit mode {} from the last run over the configured {}",
+      names(&state.modes),
+      names(&config.modes)
+    );
+    config.modes.clone_from(&state.modes);
+  } else {
+    info!(
+      "Not restoring inhibit mode {} from the last run, as the configured \
+       modes changed to {} since",
+      names(&state.modes),
+      names(&config.modes)
+    );
+    state.modes.clear();
+  }
+}
+
+fn fmt_duration(duration: Duration) -> DurationString {
+  DurationString::from(duration)
+}
+
+fn unix_epoch(time: SystemTime) -> u64 {
+  time
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .expect("Failed to convert to UNIX epoch time")
+    .as_secs()
+}
+
+// how far the wall clock may move apart from the monotonic one before
+// a --clock realtime deadline follows it
+const CLOCK_SLACK: Duration = Duration::from_secs(1);
+
+// the furthest a deadline goes, where it saturates rather than
+// overflowing the clocks
+const LONGEST_VIGIL: Duration = Duration::from_secs(100 * 365 * 86_400);
+
+// how often --clock realtime looks for the wall clock jumping
+const WALL_CLOCK_CHECK: Duration = Duration::from_secs(30);
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+// Turn failures to claim our place on the bus into something a user
+// can act on
+fn service_error(e: zbus::Error, name: &str, bus: Bus) -> anyhow::Error {
+  match e {
+    zbus::Error::NameTaken => anyhow!(
+      "{name} is already owned on the {bus} bus; \
+       is another vigilare daemon running?"
+    ),
+    e => anyhow::Error::new(e).context("failed to set up D-Bus service"),
+  }
+}
+
+// Like `recv`, but waits forever on a source that isn't set up
+async fn recv_some<T>(receiver: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+  match receiver {
+    Some(receiver) => receiver.recv().await,
+    None => std::future::pending().await,
+  }
+}
+
+fn sleep_until(at: Option<Instant>) -> tokio::time::Sleep {
+  at.map(|at| tokio::time::sleep_until(at.into()))
+    .unwrap_or_else(|| tokio::time::sleep(Duration::MAX))
+}
+
 struct DbusService {
   sender: mpsc::Sender<DaemonMessage>,
+  // a copy of the vigil's deadline and whether it's indefinite, so
+  // that the remaining time can be served without a round-trip
+  // through the daemon
+  deadline: Mutex<(Option<Instant>, Option<Instant>, bool)>,
+  // rolling vigils rely on the idle watcher
+  can_roll: bool,
+  presets: Vec<Duration>,
 }
 
+// keep the name in sync with `DbusVigilare` in protocol.rs
 #[zbus::interface(name = "org.shou.Vigilare")]
 impl DbusService {
   async fn update(&self, update: DurationUpdate) -> zbus::fdo::Result<()> {
+    self.send_update(update, Default::default()).await
+  }
+
+  async fn update_with(
+    &self,
+    update: DurationUpdate,
+    options: UpdateOptions,
+  ) -> zbus::fdo::Result<()> {
+    if options.rolling && !self.can_roll {
+      return Err(zbus::fdo::Error::NotSupported(
+        "rolling vigils need the daemon to run with --idle-timeout".into(),
+      ));
+    }
+
+    self.send_update(update, options).await
+  }
+
+  /// The configured preset durations, for front-ends to offer
+  async fn presets(&self) -> Vec<String> {
+    self
+      .presets
+      .iter()
+      .copied()
+      .map(helper::format_duration)
+      .collect()
+  }
+
+  /// Multiply the time left by `factor`, e.g. 1.5 for half as long
+  /// again
+  async fn extend_by_factor(&self, factor: f64) -> zbus::fdo::Result<()> {
+    helper::check_factor(factor).map_err(zbus::fdo::Error::InvalidArgs)?;
+
+    let (reply, receiver) = oneshot::channel();
     self
       .sender
-      .send(DaemonMessage::DurationUpdate(update))
+      .send(DaemonMessage::ExtendByFactor(factor, reply))
       .await
       .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
-    Ok(())
+    receiver
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+      .map_err(zbus::fdo::Error::Failed)
+  }
+
+  /// Switch to another inhibit mode, kept across restarts
+  async fn set_mode(&self, mode: &str) -> zbus::fdo::Result<()> {
+    let mode = mode.parse().map_err(|e: anyhow::Error| {
+      zbus::fdo::Error::InvalidArgs(e.to_string())
+    })?;
+
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::SetMode(mode, reply))
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+    receiver
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+      .map_err(zbus::fdo::Error::Failed)
+  }
+
+  /// The inhibit modes now in effect, comma-separated, whenever they
+  /// change and once the daemon is up
+  #[zbus(signal)]
+  async fn mode_changed(
+    ctx: &SignalContext<'_>,
+    mode: &str,
+  ) -> zbus::Result<()>;
+
+  /// End the vigil if it carries the label `name`, so that one script
+  /// doesn't end a vigil another one set
+  async fn cancel(&self, name: String) -> zbus::fdo::Result<()> {
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::Cancel(name, reply))
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+    receiver
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+      .map_err(zbus::fdo::Error::Failed)
+  }
+
+  /// The running timers by name, with the seconds each has left
+  async fn timers(&self) -> zbus::fdo::Result<Vec<(String, u64)>> {
+    Ok(self.status().await?.timers())
+  }
+
+  /// Seconds until the vigil ends, 0 when inactive and u64::MAX when
+  /// it has no deadline
+  #[zbus(property)]
+  async fn remaining_seconds(&self) -> u64 {
+    let (wake_until, locked_at, indefinite) = *self.deadline.lock().unwrap();
+    match wake_until {
+      _ if indefinite => u64::MAX,
+      None => 0,
+      Some(at) => {
+        // partial seconds count as whole ones, as in the client, and
+        // stand still while the session is locked
+        let now = locked_at.unwrap_or_else(Instant::now);
+        let left = at.saturating_duration_since(now);
+        left.as_secs() + u64::from(left.subsec_nanos() > 0)
+      }
+    }
   }
 
   #[zbus(property)]
@@ -223,7 +1570,456 @@ impl DbusService {
   }
 }
 
-enum DaemonMessage {
-  DurationUpdate(DurationUpdate),
+impl DbusService {
+  async fn send_update(
+    &self,
+    update: DurationUpdate,
+    options: UpdateOptions,
+  ) -> zbus::fdo::Result<()> {
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::DurationUpdate(update, options, reply))
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+
+    receiver
+      .await
+      .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+      .map_err(zbus::fdo::Error::Failed)
+  }
+}
+
+/// Answers an update once it has been applied, with the error if the
+/// inhibitor couldn't follow
+pub type UpdateReply = oneshot::Sender<Result<(), String>>;
+
+pub enum DaemonMessage {
+  DurationUpdate(DurationUpdate, UpdateOptions, UpdateReply),
+  ExtendByFactor(f64, UpdateReply),
+  SetMode(InhibitMode, UpdateReply),
+  Cancel(String, UpdateReply),
   StatusRequest(oneshot::Sender<Status>),
+  MetricsRequest(oneshot::Sender<Metrics>),
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    client::Client,
+    helper::parse_duration_update,
+    inhibitor::mock::{self, MockInhibitor},
+  };
+
+  fn mock_daemon(config: Config) -> Daemon {
+    let (inhibitor, _) = MockInhibitor::new();
+    Daemon::with_inhibitor(&config, Connections::default(), Box::new(inhibitor))
+  }
+
+  fn mins(m: u64) -> Duration {
+    Duration::from_secs(m * 60)
+  }
+
+  fn update(daemon: &mut Daemon, update: &str) {
+    let update = parse_duration_update(update).unwrap();
+    daemon.update_duration(update, &Default::default()).unwrap();
+  }
+
+  // the time left, give or take the time the test took
+  fn assert_left(daemon: &Daemon, expected: Duration) {
+    let left = daemon
+      .wake_until
+      .expect("no deadline")
+      .saturating_duration_since(Instant::now());
+    assert!(
+      left <= expected && left + Duration::from_secs(1) > expected,
+      "{left:?} left, expected {expected:?}"
+    );
+  }
+
+  #[test]
+  fn max_extend_limits_each_extension() {
+    let mut daemon = mock_daemon(Config {
+      max_extend: Some(mins(10)),
+      ..Config::default()
+    });
+    update(&mut daemon, "30m");
+    update(&mut daemon, "+1h");
+    assert_left(&daemon, mins(40));
+    update(&mut daemon, "+5m");
+    assert_left(&daemon, mins(45));
+    // setting is no extension
+    update(&mut daemon, "2h");
+    assert_left(&daemon, mins(120));
+  }
+
+  #[test]
+  fn max_duration_bounds_the_extensions() {
+    let mut daemon = mock_daemon(Config {
+      max_extend: Some(mins(10)),
+      max_duration: Some(mins(30)),
+      ..Config::default()
+    });
+    update(&mut daemon, "25m");
+    update(&mut daemon, "+10m");
+    assert_left(&daemon, mins(30));
+    update(&mut daemon, "-20m");
+    update(&mut daemon, "+1h");
+    assert_left(&daemon, mins(20));
+  }
+
+  #[test]
+  fn huge_extensions_saturate() {
+    let mut daemon = mock_daemon(Config::default());
+    update(&mut daemon, "1h");
+    update(&mut daemon, "+10000000000000000000s");
+    assert_left(&daemon, LONGEST_VIGIL);
+    update(&mut daemon, "+1h");
+    assert_left(&daemon, LONGEST_VIGIL);
+    assert!(daemon.status().active);
+
+    let mut daemon = mock_daemon(Config {
+      max_duration: Some(Duration::MAX),
+      ..Config::default()
+    });
+    update(&mut daemon, "+10000000000000000000s");
+    assert_left(&daemon, LONGEST_VIGIL);
+  }
+
+  #[test]
+  fn huge_vigils_saturate() {
+    let mut daemon = mock_daemon(Config::default());
+    update(&mut daemon, "1h");
+    let update = DurationUpdate::Set(Duration::MAX);
+    daemon.update_duration(update, &Default::default()).unwrap();
+    assert_left(&daemon, LONGEST_VIGIL);
+  }
+
+  #[test]
+  fn zero_and_subtracting_past_the_deadline_both_clear() {
+    for clear in ["0", "-2h", "-10000000000000000000s"] {
+      let mut daemon = mock_daemon(Config::default());
+      update(&mut daemon, "1h");
+      update(&mut daemon, clear);
+      assert!(!daemon.vigil_active(), "{clear} didn't clear");
+      assert_eq!(daemon.wake_until, None);
+      assert_eq!(daemon.started_at, None);
+    }
+  }
+
+  #[test]
+  fn the_shortest_vigil_arms() {
+    let mut daemon = mock_daemon(Config::default());
+    let update = DurationUpdate::Set(Duration::from_nanos(1));
+    daemon.update_duration(update, &Default::default()).unwrap();
+    assert!(daemon.vigil_active());
+    assert!(daemon.started_at.is_some());
+  }
+
+  async fn extend_by_factor(daemon: &mut Daemon, factor: f64) {
+    let (reply, _) = oneshot::channel();
+    let event = DaemonEvent::ExtendByFactor(factor, reply);
+    let flow = daemon.handle_event(event).await.unwrap();
+    assert!(flow.is_continue());
+  }
+
+  #[tokio::test]
+  async fn huge_factors_saturate() {
+    let mut daemon = mock_daemon(Config::default());
+    update(&mut daemon, "1h");
+    extend_by_factor(&mut daemon, f64::MAX).await;
+    assert_left(&daemon, LONGEST_VIGIL);
+
+    let mut daemon = mock_daemon(Config {
+      max_duration: Some(mins(90)),
+      ..Config::default()
+    });
+    update(&mut daemon, "1h");
+    extend_by_factor(&mut daemon, 1e300).await;
+    assert_left(&daemon, mins(90));
+  }
+
+  #[tokio::test]
+  async fn failing_to_inhibit_is_retried_on_reassert() {
+    let (inhibitor, mock) = MockInhibitor::failing();
+    let config = Config {
+      reassert_interval: Some(mins(1)),
+      ..Config::default()
+    };
+    let mut daemon = Daemon::with_inhibitor(
+      &config,
+      Connections::default(),
+      Box::new(inhibitor),
+    );
+
+    update(&mut daemon, "15m");
+    daemon.follow_inhibitor().await;
+    assert!(!daemon.inhibited);
+    assert_eq!(daemon.metrics().inhibit_failures, 1);
+    assert!(daemon.reassert_at.is_some());
+
+    mock.lock().unwrap().failing = false;
+    let flow = daemon.handle_event(DaemonEvent::Reassert).await.unwrap();
+    assert!(flow.is_continue());
+    assert!(daemon.inhibited);
+    assert!(mock.lock().unwrap().held);
+    assert_eq!(daemon.metrics().inhibit_failures, 1);
+  }
+
+  #[tokio::test]
+  async fn status_tells_of_the_modes_left_out() {
+    let (good, _) = MockInhibitor::new();
+    let (bad, failing) = MockInhibitor::failing();
+    let inhibitor = mock::composite(vec![
+      (InhibitMode::Logind, good),
+      (InhibitMode::Xscreensaver, bad),
+    ]);
+    let mut daemon = Daemon::with_inhibitor(
+      &Config::default(),
+      Connections::default(),
+      inhibitor,
+    );
+
+    update(&mut daemon, "15m");
+    daemon.follow_inhibitor().await;
+    assert!(daemon.inhibited);
+    assert_eq!(daemon.status().degraded, "xscreensaver: inhibit failed");
+
+    failing.lock().unwrap().failing = false;
+    daemon.inhibitor.refresh().await.unwrap();
+    assert_eq!(daemon.status().degraded, "");
+
+    // nothing is degraded while nothing is held
+    update(&mut daemon, "0");
+    daemon.follow_inhibitor().await;
+    assert!(!daemon.inhibited);
+    assert_eq!(daemon.status().degraded, "");
+  }
+
+  #[test]
+  fn small_clock_drifts_add_up_before_they_are_followed() {
+    let mut daemon = mock_daemon(Config {
+      warn_before: Some(mins(5)),
+      ..Config::default()
+    });
+    update(&mut daemon, "15m");
+    let deadline = daemon.wake_until;
+    daemon.warned_for = deadline;
+    let (mono, wall) = (Instant::now(), SystemTime::now());
+
+    // the wall clock ran a little behind, twice
+    for behind in [400, 800] {
+      let wall = wall + Duration::from_millis(behind);
+      daemon.clock_anchor = Some((mono, wall));
+      daemon.follow_wall_clock();
+      assert_eq!(daemon.wake_until, deadline);
+      assert_eq!(daemon.clock_anchor, Some((mono, wall)));
+    }
+
+    // the wall clock was set forward by a minute
+    daemon.clock_anchor = Some((mono, wall - mins(1)));
+    daemon.follow_wall_clock();
+    assert_left(&daemon, mins(14));
+    assert_ne!(daemon.clock_anchor, Some((mono, wall - mins(1))));
+    // which is no new deadline to warn about
+    assert_eq!(daemon.warned_for, daemon.wake_until);
+    assert_eq!(daemon.warn_at(), None);
+  }
+
+  #[test]
+  fn one_shot_vigils_are_not_kept() {
+    let path = std::env::temp_dir()
+      .join(format!("vigilare-test-{}.state", std::process::id()));
+    let mut daemon = mock_daemon(Config::default());
+    daemon.state_path = Some(path.clone());
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    let mut state = State::default();
+    state.indefinite = true;
+    daemon.restore_vigil(&state);
+    assert!(daemon.indefinite);
+
+    daemon.once(mins(10)).unwrap();
+    assert!(!daemon.indefinite);
+    assert_left(&daemon, mins(10));
+    assert!(daemon.exit_on_deadline);
+    daemon.save_state();
+    assert!(!path.exists());
+
+    // whereas a vigil of a daemon that stays is
+    let mut daemon = mock_daemon(Config::default());
+    daemon.state_path = Some(path.clone());
+    update(&mut daemon, "10m");
+    daemon.save_state();
+    let state = State::load(&path).unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let left = state.wake_until - now.unwrap().as_secs();
+    assert!((599..=600).contains(&left), "{left}s left");
+  }
+
+  #[test]
+  fn switched_modes_last_until_the_configured_ones_change() {
+    let mut state = State::default();
+    state.modes = vec![InhibitMode::Logind];
+    state.configured = vec![InhibitMode::Auto];
+
+    let mut config = Config {
+      modes: vec![InhibitMode::Auto],
+      ..Config::default()
+    };
+    restore_modes(&mut config, &mut state);
+    assert_eq!(config.modes, [InhibitMode::Logind]);
+
+    let mut config = Config {
+      modes: vec![InhibitMode::Xscreensaver],
+      ..Config::default()
+    };
+    restore_modes(&mut config, &mut state);
+    assert_eq!(config.modes, [InhibitMode::Xscreensaver]);
+    assert!(state.modes.is_empty());
+  }
+
+  #[test]
+  fn switched_modes_remember_what_was_configured() {
+    let mut daemon = mock_daemon(Config {
+      modes: vec![InhibitMode::Auto],
+      ..Config::default()
+    });
+    let path = std::env::temp_dir()
+      .join(format!("vigilare-modes-{}.state", std::process::id()));
+    daemon.state_path = Some(path.clone());
+    daemon.config.modes = vec![InhibitMode::Logind];
+    daemon.modes_switched = true;
+    daemon.save_state();
+
+    let state = State::load(&path).unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(state.modes, [InhibitMode::Logind]);
+    assert_eq!(state.configured, [InhibitMode::Auto]);
+  }
+
+  async fn lock(daemon: &mut Daemon, locked: bool) {
+    let flow = daemon.handle_event(DaemonEvent::Locked(locked)).await;
+    assert!(flow.unwrap().is_continue());
+  }
+
+  // what the status and metrics say is left, in seconds
+  fn reported_left(daemon: &Daemon) -> (u64, Option<u64>) {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    let status = daemon.status().wake_until - now.unwrap().as_secs();
+    (status, daemon.metrics().remaining_seconds)
+  }
+
+  #[tokio::test]
+  async fn vigils_pause_while_locked() {
+    let mut daemon = mock_daemon(Config {
+      release_on_lock: true,
+      ..Config::default()
+    });
+    update(&mut daemon, "30m");
+    lock(&mut daemon, true).await;
+    assert!(!daemon.keep_awake());
+
+    // as if the lock had started an hour ago
+    let hour = mins(60);
+    daemon.locked_at = daemon.locked_at.map(|at| at - hour);
+    daemon.wake_until = daemon.wake_until.map(|at| at - hour);
+    assert!(matches!(reported_left(&daemon), (1799..=1800, Some(1800))));
+    assert_eq!(daemon.deadline(), None);
+
+    lock(&mut daemon, false).await;
+    assert_left(&daemon, mins(30));
+    assert!(daemon.keep_awake());
+  }
+
+  #[tokio::test]
+  async fn vigils_set_while_locked_start_on_unlock() {
+    let mut daemon = mock_daemon(Config {
+      release_on_lock: true,
+      ..Config::default()
+    });
+    lock(&mut daemon, true).await;
+    daemon.locked_at = daemon.locked_at.map(|at| at - mins(60));
+
+    update(&mut daemon, "10m");
+    assert!(matches!(reported_left(&daemon), (599..=600, Some(600))));
+    update(&mut daemon, "+5m");
+    assert!(matches!(reported_left(&daemon), (899..=900, Some(900))));
+
+    lock(&mut daemon, false).await;
+    assert_left(&daemon, mins(15));
+  }
+
+  // the two ends of a connection with no bus in between, the first
+  // serving and the second calling on it
+  async fn private_bus() -> (zbus::Connection, zbus::Connection) {
+    let (ours, theirs) = tokio::net::UnixStream::pair().unwrap();
+    let guid = zbus::Guid::generate();
+    let server = zbus::connection::Builder::unix_stream(ours)
+      .server(guid)
+      .unwrap()
+      .p2p()
+      .build();
+    let client = zbus::connection::Builder::unix_stream(theirs).p2p().build();
+    tokio::try_join!(server, client).unwrap()
+  }
+
+  // a client once the daemon serves on the other end
+  async fn served_client(conn: &zbus::Connection) -> Client {
+    let instance = Config::default().instance;
+    for _ in 0..100 {
+      let mut client = Client::over_dbus(conn, &instance).await.unwrap();
+      if client.status().await.is_ok() {
+        return client;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the daemon never served");
+  }
+
+  #[tokio::test]
+  async fn updates_round_trip_through_the_daemon() {
+    let (daemon_end, client_end) = private_bus().await;
+    let (inhibitor, mock) = MockInhibitor::new();
+    let connections = Connections::with(Bus::Session, daemon_end);
+    let mut daemon = Daemon::with_inhibitor(
+      &Config::default(),
+      connections,
+      Box::new(inhibitor),
+    );
+    let (stop, stopped) = oneshot::channel::<()>();
+    let daemon = tokio::spawn(async move { daemon.run(stopped).await });
+
+    let mut client = served_client(&client_end).await;
+    let status = client.status().await.unwrap();
+    assert!(!status.active);
+
+    let update = parse_duration_update("15m").unwrap();
+    let options = UpdateOptions {
+      label: "test".into(),
+      ..Default::default()
+    };
+    client.update(update, options).await.unwrap();
+    let status = client.status().await.unwrap();
+    assert!(status.active);
+    assert_eq!(status.label, "test");
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    let now = now.unwrap();
+    let left = status.wake_until.saturating_sub(now.as_secs());
+    assert!((899..=900).contains(&left), "{left}s left");
+    assert!(mock.lock().unwrap().held);
+
+    client.extend_by_factor(2.0).await.unwrap();
+    let status = client.status().await.unwrap();
+    let left = status.wake_until.saturating_sub(now.as_secs());
+    assert!((1798..=1800).contains(&left), "{left}s left");
+
+    client.clear().await.unwrap();
+    let status = client.status().await.unwrap();
+    assert!(!status.active);
+    assert!(!mock.lock().unwrap().held);
+
+    stop.send(()).unwrap();
+    daemon.await.unwrap().unwrap();
+  }
 }
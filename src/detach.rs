@@ -0,0 +1,129 @@
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{Read as _, Write as _},
+  os::fd::AsRawFd as _,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use nix::unistd::{self, ForkResult};
+use tracing::warn;
+
+use crate::protocol::Instance;
+
+/// Default place for the PID file of a detached daemon
+pub fn default_pid_file(instance: &Instance) -> PathBuf {
+  let dir = std::env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(std::env::temp_dir);
+  dir.join(instance.file_name("pid"))
+}
+
+/// Held by a daemon that was moved into the background. The process
+/// that started it waits until `ready` is called.
+pub struct Detached {
+  ready_pipe: Option<File>,
+  pid_file: PathBuf,
+}
+
+/// Move the process into the background, the usual double-fork way.
+///
+/// This has to run before any threads are spawned, i.e. before the
+/// tokio runtime is built. Only the background process returns; the
+/// original one exits once the daemon is ready, or fails if the daemon
+/// dies before that. Output keeps going to the terminal until then.
+pub fn detach(pid_file: &Path) -> Result<Detached> {
+  // written once ready, after leaving the working directory
+  let pid_file = std::path::absolute(pid_file).with_context(|| {
+    format!("failed to resolve PID file {}", pid_file.display())
+  })?;
+
+  let (read_end, write_end) =
+    unistd::pipe().context("failed to create pipe")?;
+
+  // SAFETY: no other threads are running yet
+  match unsafe { unistd::fork() }.context("failed to fork")? {
+    ForkResult::Parent { .. } => {
+      drop(write_end);
+      wait_ready(File::from(read_end));
+    }
+    ForkResult::Child => drop(read_end),
+  }
+
+  unistd::setsid().context("failed to start a new session")?;
+
+  // fork again so that the daemon can never regain a terminal
+  // SAFETY: still single threaded
+  if let ForkResult::Parent { .. } =
+    unsafe { unistd::fork() }.context("failed to fork")?
+  {
+    std::process::exit(0);
+  }
+
+  unistd::chdir("/").context("failed to change directory")?;
+
+  Ok(Detached {
+    ready_pipe: Some(File::from(write_end)),
+    pid_file,
+  })
+}
+
+// the daemon writes a byte once it's ready, and the pipe closes
+// without one if it exits before that
+fn wait_ready(mut pipe: File) -> ! {
+  let mut buf = [0];
+  match pipe.read(&mut buf) {
+    Ok(1) => std::process::exit(0),
+    _ => {
+      eprintln!("vigilare daemon exited before it was ready");
+      std::process::exit(1);
+    }
+  }
+}
+
+fn redirect_stdio() -> Result<()> {
+  let null = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/null")
+    .context("failed to open /dev/null")?;
+
+  for fd in 0..=2 {
+    unistd::dup2(null.as_raw_fd(), fd).context("failed to redirect stdio")?;
+  }
+  Ok(())
+}
+
+impl Detached {
+  /// Write the PID file, let go of the terminal and let the original
+  /// process exit
+  pub fn ready(&mut self) -> Result<()> {
+    let Some(pipe) = &mut self.ready_pipe else {
+      return Ok(());
+    };
+
+    let pid = std::process::id();
+    fs::write(&self.pid_file, format!("{pid}\n")).with_context(|| {
+      format!("failed to write PID file {}", self.pid_file.display())
+    })?;
+
+    // startup errors still reach the terminal, the rest doesn't
+    redirect_stdio()?;
+
+    pipe.write_all(&[1]).context("failed to signal readiness")?;
+    self.ready_pipe = None;
+    Ok(())
+  }
+}
+
+impl Drop for Detached {
+  fn drop(&mut self) {
+    // only remove the PID file if we wrote it
+    if self.ready_pipe.is_some() {
+      return;
+    }
+    if let Err(e) = fs::remove_file(&self.pid_file) {
+      warn!("Failed to remove PID file {}: {e}", self.pid_file.display());
+    }
+  }
+}
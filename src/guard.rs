@@ -0,0 +1,209 @@
+//! Activity guards, keeping the machine awake while some condition
+//! holds regardless of the vigil
+
+use std::{
+  fs,
+  path::Path,
+  process::{Command, Stdio},
+  thread,
+  time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Whether a process with the given name is running, matched against
+/// both its command name and the file name of the program it runs
+pub fn process_running(name: &str) -> bool {
+  let Ok(entries) = fs::read_dir("/proc") else {
+    return false;
+  };
+
+  entries.flatten().any(|entry| {
+    let dir = entry.path();
+    let is_pid = entry
+      .file_name()
+      .to_str()
+      .is_some_and(|s| s.bytes().all(|b| b.is_ascii_digit()));
+    is_pid && process_matches(&dir, name)
+  })
+}
+
+// the command name is cut to 15 bytes, so also look at argv[0]
+fn process_matches(dir: &Path, name: &str) -> bool {
+  if let Ok(comm) = fs::read_to_string(dir.join("comm")) {
+    if comm.trim_end() == name {
+      return true;
+    }
+  }
+
+  let Ok(cmdline) = fs::read(dir.join("cmdline")) else {
+    return false;
+  };
+  let argv0 = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+  let program = String::from_utf8_lossy(argv0);
+  Path::new(program.as_ref())
+    .file_name()
+    .is_some_and(|file_name| file_name == name)
+}
+
+/// Whether a shell command succeeds. Its output is discarded.
+pub fn command_succeeds(command: &str) -> bool {
+  let status = Command::new("sh")
+    .args(["-c", command])
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status();
+  match status {
+    Ok(status) => status.success(),
+    Err(e) => {
+      warn!("Failed to run {command:?}: {e}");
+      false
+    }
+  }
+}
+
+/// Bytes received and sent so far over all interfaces but loopback
+pub fn network_bytes() -> u64 {
+  let Ok(entries) = fs::read_dir("/sys/class/net") else {
+    return 0;
+  };
+
+  entries
+    .flatten()
+    .filter(|entry| entry.file_name() != "lo")
+    .flat_map(|entry| {
+      let stats = entry.path().join("statistics");
+      ["rx_bytes", "tx_bytes"].map(|counter| {
+        fs::read_to_string(stats.join(counter))
+          .ok()
+          .and_then(|s| s.trim().parse::<u64>().ok())
+          .unwrap_or(0)
+      })
+    })
+    .sum()
+}
+
+/// Tells whether the network is busy from samples of `network_bytes`
+pub struct NetworkActivity {
+  // bytes per second
+  threshold: u64,
+  // how long it stays busy after the last sample over the threshold
+  quiet: Duration,
+  last_bytes: u64,
+  last_at: Instant,
+  busy_at: Option<Instant>,
+}
+
+impl NetworkActivity {
+  pub fn new(threshold: u64, quiet: Duration) -> Self {
+    Self {
+      threshold,
+      quiet,
+      last_bytes: network_bytes(),
+      last_at: Instant::now(),
+      busy_at: None,
+    }
+  }
+
+  /// Take a sample, returning whether the network counts as busy
+  pub fn check(&mut self) -> bool {
+    let (bytes, now) = (network_bytes(), Instant::now());
+    // counters start over when an interface goes away
+    let moved = bytes.saturating_sub(self.last_bytes);
+    let elapsed = now.duration_since(self.last_at).as_secs_f64();
+    (self.last_bytes, self.last_at) = (bytes, now);
+
+    if elapsed > 0.0 && moved as f64 / elapsed > self.threshold as f64 {
+      self.busy_at = Some(now);
+    }
+    self
+      .busy_at
+      .is_some_and(|at| now.duration_since(at) < self.quiet)
+  }
+}
+
+/// Re-check a condition every `interval` on a thread of its own. The
+/// receiver gets the new value whenever it differs from the last one,
+/// starting from `initial`.
+pub fn poll<F>(
+  interval: Duration,
+  initial: bool,
+  check: F,
+) -> mpsc::Receiver<bool>
+where
+  F: FnMut() -> bool + Send + 'static,
+{
+  let (sender, receiver) = mpsc::channel(1);
+  thread::spawn(move || watch(interval, initial, check, &sender));
+  receiver
+}
+
+/// Like `poll`, but makes the first check on that thread too and
+/// waits for it, for checks too slow to make on the runtime
+pub async fn poll_checked<F>(
+  interval: Duration,
+  mut check: F,
+) -> (bool, mpsc::Receiver<bool>)
+where
+  F: FnMut() -> bool + Send + 'static,
+{
+  let (sender, receiver) = mpsc::channel(1);
+  let (first, initial) = oneshot::channel();
+
+  thread::spawn(move || {
+    let initial = check();
+    if first.send(initial).is_ok() {
+      watch(interval, initial, check, &sender);
+    }
+  });
+
+  // the thread only goes away without an answer if the check panics
+  (initial.await.unwrap_or(false), receiver)
+}
+
+fn watch<F>(
+  interval: Duration,
+  mut last: bool,
+  mut check: F,
+  sender: &mpsc::Sender<bool>,
+) where
+  F: FnMut() -> bool,
+{
+  while !sender.is_closed() {
+    thread::sleep(interval);
+    let now = check();
+    if now != last && sender.blocking_send(now).is_err() {
+      break;
+    }
+    last = now;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn the_first_check_is_made_off_the_runtime() {
+    let runtime = thread::current().id();
+    let (holds, _changes) = poll_checked(Duration::from_secs(60), move || {
+      thread::current().id() != runtime
+    })
+    .await;
+    assert!(holds);
+  }
+
+  #[tokio::test]
+  async fn changes_are_sent_after_the_first_check() {
+    let mut checks = [true, true, false].into_iter();
+    let (holds, mut changes) =
+      poll_checked(Duration::from_millis(1), move || {
+        checks.next().unwrap_or(false)
+      })
+      .await;
+    assert!(holds);
+    assert_eq!(changes.recv().await, Some(false));
+  }
+}
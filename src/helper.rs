@@ -1,23 +1,327 @@
-use std::{str::FromStr as _, time::Duration};
+use std::time::Duration;
 
 use duration_string::DurationString;
 
 use crate::protocol::DurationUpdate;
 
 pub fn parse_duration_update(s: &str) -> Result<DurationUpdate, String> {
-  match &s[..1] {
-    "+" => {
-      let duration = DurationString::from_str(&s[1..])?.into();
-      Ok(DurationUpdate::Add(duration))
-    }
-    "-" => {
-      let duration = DurationString::from_str(&s[1..])?.into();
-      Ok(DurationUpdate::Sub(duration))
-    }
-    "0" => Ok(DurationUpdate::Set(Duration::ZERO)),
-    _ => {
-      let duration = DurationString::from_str(s)?.into();
-      Ok(DurationUpdate::Set(duration))
+  let invalid = || format!("invalid duration '{s}'; try 30m, 1h, 2d, or +/-1h");
+
+  if let Some(rest) = s.strip_prefix('+') {
+    let duration = parse(rest).ok_or_else(invalid)?;
+    Ok(DurationUpdate::Add(duration))
+  } else if let Some(rest) = s.strip_prefix('-') {
+    let duration = parse(rest).ok_or_else(invalid)?;
+    Ok(DurationUpdate::Sub(duration))
+  } else if s == "0" {
+    Ok(DurationUpdate::Set(Duration::ZERO))
+  } else {
+    let duration = parse(s).ok_or_else(invalid)?;
+    Ok(DurationUpdate::Set(duration))
+  }
+}
+
+/// Like `parse_duration_update`, but "-" reads the update from a line
+/// on stdin
+pub fn parse_duration_update_arg(s: &str) -> Result<DurationUpdate, String> {
+  if s != "-" {
+    return parse_duration_update(s);
+  }
+
+  let mut line = String::new();
+  std::io::stdin()
+    .read_line(&mut line)
+    .map_err(|e| format!("failed to read stdin: {e}"))?;
+  match line.trim() {
+    "" => Err("no duration on stdin".to_string()),
+    update => parse_duration_update(update),
+  }
+}
+
+/// A factor to multiply the remaining time of a vigil by
+pub fn parse_factor(s: &str) -> Result<f64, String> {
+  let factor = s
+    .parse()
+    .map_err(|_| format!("invalid factor '{s}'; try 1.5 or 2"))?;
+  check_factor(factor)?;
+  Ok(factor)
+}
+
+pub fn check_factor(factor: f64) -> Result<(), String> {
+  if !factor.is_finite() || factor < 0.0 {
+    return Err(format!("factor {factor} is not a non-negative number"));
+  }
+  Ok(())
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+  parse(s).ok_or_else(|| format!("invalid duration '{s}'; try 30m, 1h or 2d"))
+}
+
+// the error from duration_string doesn't name the input, callers
+// build their own
+fn parse(s: &str) -> Option<Duration> {
+  if s.contains('.') {
+    return parse_fractional(s);
+  }
+  parse_whole(s)
+}
+
+// The syntax of duration_string, whole numbers with units and
+// several of them summed up like "1h30m", but checked for overflow,
+// which duration_string panics on
+fn parse_whole(s: &str) -> Option<Duration> {
+  let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+  let mut rest = s.as_str();
+  if rest.is_empty() {
+    return None;
+  }
+
+  let mut total = Duration::ZERO;
+  while !rest.is_empty() {
+    let unit_at = rest
+      .find(|c: char| !c.is_ascii_digit())
+      .unwrap_or(rest.len());
+    let (value, tail) = rest.split_at(unit_at);
+    let value_at = tail
+      .find(|c: char| c.is_ascii_digit())
+      .unwrap_or(tail.len());
+    let (unit, tail) = tail.split_at(value_at);
+    rest = tail;
+
+    let value: u64 = value.parse().ok()?;
+    let secs = Duration::from_secs(value);
+    let duration = match unit {
+      "ns" => Duration::from_nanos(value),
+      "us" => Duration::from_micros(value),
+      "ms" => Duration::from_millis(value),
+      "s" => secs,
+      "m" => secs.checked_mul(60)?,
+      "h" => secs.checked_mul(3_600)?,
+      "d" => secs.checked_mul(86_400)?,
+      "w" => secs.checked_mul(604_800)?,
+      // the year duration_string formats with
+      "y" => secs.checked_mul(31_556_926)?,
+      _ => return None,
+    };
+    total = total.checked_add(duration)?;
+  }
+  Some(total)
+}
+
+// duration_string only takes whole numbers, handle a single decimal
+// figure like "1.5h" ourselves, to millisecond precision
+fn parse_fractional(s: &str) -> Option<Duration> {
+  let split = s.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+  let (value, unit) = s.split_at(split);
+  let value: f64 = value.parse().ok()?;
+
+  let unit_millis = match unit {
+    "ms" => 1.0,
+    "s" => 1_000.0,
+    "m" => 60_000.0,
+    "h" => 3_600_000.0,
+    "d" => 86_400_000.0,
+    "w" => 604_800_000.0,
+    _ => return None,
+  };
+
+  let millis = (value * unit_millis).round();
+  (millis < u64::MAX as f64).then(|| Duration::from_millis(millis as u64))
+}
+
+/// A time of day on the local clock, e.g. "23:30"
+#[derive(Debug, Clone, Copy)]
+pub struct ClockTime {
+  hour: u8,
+  minute: u8,
+}
+
+pub fn parse_clock_time(s: &str) -> Result<ClockTime, String> {
+  let invalid = || format!("invalid time '{s}'; try 23:30 or 00:00");
+  let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+  if minute.len() != 2 {
+    return Err(invalid());
+  }
+  let hour: u8 = hour.parse().map_err(|_| invalid())?;
+  let minute: u8 = minute.parse().map_err(|_| invalid())?;
+  if hour > 23 || minute > 59 {
+    return Err(invalid());
+  }
+  Ok(ClockTime { hour, minute })
+}
+
+impl ClockTime {
+  /// How long until the clock next shows this time, tomorrow if it
+  /// has passed today
+  pub fn until_next(self) -> Duration {
+    let now = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap_or_default();
+    let Ok(now_secs) = libc::time_t::try_from(now.as_secs()) else {
+      return Duration::ZERO;
+    };
+
+    // SAFETY: localtime_r and mktime only touch the struct given to
+    // them
+    let target = unsafe {
+      let mut tm = std::mem::zeroed::<libc::tm>();
+      if libc::localtime_r(&now_secs, &mut tm).is_null() {
+        return Duration::ZERO;
+      }
+      tm.tm_hour = self.hour.into();
+      tm.tm_min = self.minute.into();
+      tm.tm_sec = 0;
+      // let mktime work out daylight saving time for the target
+      tm.tm_isdst = -1;
+      let mut target = libc::mktime(&mut tm);
+      if target <= now_secs {
+        // mktime normalizes the day past the end of the month
+        tm.tm_mday += 1;
+        tm.tm_isdst = -1;
+        target = libc::mktime(&mut tm);
+      }
+      target
+    };
+
+    let target = Duration::from_secs(u64::try_from(target).unwrap_or(0));
+    target.saturating_sub(now)
+  }
+}
+
+/// Format a duration in the syntax `parse_duration` accepts
+pub fn format_duration(duration: Duration) -> String {
+  // DurationString would make it "0y"
+  if duration.is_zero() {
+    return "0s".to_string();
+  }
+  DurationString::from(duration).to_string()
+}
+
+/// Serialize a `Duration` in the same "1h"/"30m" syntax accepted on
+/// the command line.
+pub mod duration_str {
+  use std::time::Duration;
+
+  use duration_string::DurationString;
+  use serde::Serializer;
+
+  pub fn serialize<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&DurationString::from(*duration).to_string())
+  }
+}
+
+pub mod durations_str {
+  use std::time::Duration;
+
+  use serde::{ser::SerializeSeq as _, Serializer};
+
+  pub fn serialize<S: Serializer>(
+    durations: &[Duration],
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(durations.len()))?;
+    for duration in durations {
+      seq.serialize_element(&super::format_duration(*duration))?;
+    }
+    seq.end()
+  }
+}
+
+pub mod option_duration_str {
+  use std::time::Duration;
+
+  use serde::Serializer;
+
+  pub fn serialize<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    match duration {
+      Some(duration) => super::duration_str::serialize(duration, serializer),
+      None => serializer.serialize_none(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use proptest::prelude::*;
+
+  use super::*;
+
+  fn mins(m: u64) -> Duration {
+    Duration::from_secs(m * 60)
+  }
+
+  #[test]
+  fn parses_fractional_durations() {
+    assert_eq!(parse_duration("0.5h"), Ok(mins(30)));
+    assert_eq!(parse_duration("1.5h"), Ok(mins(90)));
+    assert_eq!(parse_duration("2.5m"), Ok(Duration::from_secs(150)));
+    assert_eq!(parse_duration("0.25s"), Ok(Duration::from_millis(250)));
+  }
+
+  #[test]
+  fn parses_fractional_updates() {
+    let parse = parse_duration_update;
+    assert_eq!(
+      parse("+2.5m"),
+      Ok(DurationUpdate::Add(Duration::from_secs(150)))
+    );
+    assert_eq!(parse("-0.5h"), Ok(DurationUpdate::Sub(mins(30))));
+    assert_eq!(parse("1.5h"), Ok(DurationUpdate::Set(mins(90))));
+    assert_eq!(parse("0.0m"), Ok(DurationUpdate::Set(Duration::ZERO)));
+  }
+
+  #[test]
+  fn rejects_malformed_fractions() {
+    for s in ["1.5", ".h", "1.2.3h", "1.5x", "+.", "1.5h30m"] {
+      assert!(parse_duration_update(s).is_err(), "{s} parsed");
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn parsing_never_panics(s in "\\PC*") {
+      let _ = parse_duration_update(&s);
+    }
+
+    #[test]
+    fn parsing_durations_never_panics(
+      s in "[+-]?([0-9]{0,25}(ns|us|ms|[smhdwy.x ])?){1,4}"
+    ) {
+      let _ = parse_duration_update(&s);
+    }
+
+    #[test]
+    fn overflowing_units_are_rejected(
+      value in u64::MAX / 60 + 1..,
+      unit in "[mhdwy]",
+    ) {
+      let s = format!("{value}{unit}");
+      prop_assert!(parse_duration(&s).is_err());
+    }
+
+    #[test]
+    fn overflowing_sums_are_rejected(value in 1..u64::MAX) {
+      let s = format!("{}s{value}s", u64::MAX);
+      prop_assert!(parse_duration(&s).is_err());
+    }
+
+    #[test]
+    fn formatted_durations_parse_back(nanos: u64) {
+      let duration = Duration::from_nanos(nanos);
+      prop_assert_eq!(parse_duration(&format_duration(duration)), Ok(duration));
+    }
+
+    #[test]
+    fn formatted_seconds_parse_back(secs: u64) {
+      let duration = Duration::from_secs(secs);
+      prop_assert_eq!(parse_duration(&format_duration(duration)), Ok(duration));
     }
   }
 }
@@ -0,0 +1,89 @@
+//! User idle detection with the Wayland ext-idle-notify-v1 protocol
+
+use std::{thread, time::Duration};
+
+use anyhow::{Context as _, Result};
+use tokio::sync::mpsc;
+use tracing::warn;
+use wayland_client::{
+  delegate_noop,
+  globals::{registry_queue_init, GlobalListContents},
+  protocol::{wl_registry, wl_seat},
+  Connection, Dispatch, QueueHandle,
+};
+use wayland_protocols::ext::idle_notify::v1::client::{
+  ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+  ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+
+/// Watch for user activity. The receiver gets `true` once the user has
+/// been idle for `timeout`, and `false` as soon as they are back.
+pub fn watch(timeout: Duration) -> Result<mpsc::Receiver<bool>> {
+  let conn = Connection::connect_to_env()
+    .context("failed to connect to the Wayland compositor")?;
+  let (globals, mut queue) = registry_queue_init::<State>(&conn)?;
+  let qh = queue.handle();
+
+  let seat: wl_seat::WlSeat = globals.bind(&qh, 1..=1, ())?;
+  let notifier: ExtIdleNotifierV1 = globals
+    .bind(&qh, 1..=1, ())
+    .context("compositor does not support ext-idle-notify-v1")?;
+
+  let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+  let notification = notifier.get_idle_notification(timeout_ms, &seat, &qh, ());
+
+  let (sender, receiver) = mpsc::channel(1);
+  let mut state = State { sender };
+
+  thread::spawn(move || {
+    // the notification stops once dropped, keep it with the thread
+    let _notification = notification;
+
+    while !state.sender.is_closed() {
+      if let Err(e) = queue.blocking_dispatch(&mut state) {
+        warn!("Wayland idle detection stopped: {e}");
+        break;
+      }
+    }
+  });
+
+  Ok(receiver)
+}
+
+struct State {
+  sender: mpsc::Sender<bool>,
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for State {
+  fn event(
+    state: &mut Self,
+    _notification: &ExtIdleNotificationV1,
+    event: ext_idle_notification_v1::Event,
+    _data: &(),
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+    let idle = match event {
+      ext_idle_notification_v1::Event::Idled => true,
+      ext_idle_notification_v1::Event::Resumed => false,
+      _ => return,
+    };
+
+    state.sender.blocking_send(idle).ok();
+  }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+  fn event(
+    _state: &mut Self,
+    _registry: &wl_registry::WlRegistry,
+    _event: wl_registry::Event,
+    _data: &GlobalListContents,
+    _conn: &Connection,
+    _qh: &QueueHandle<Self>,
+  ) {
+  }
+}
+
+delegate_noop!(State: ignore wl_seat::WlSeat);
+delegate_noop!(State: ExtIdleNotifierV1);
@@ -1,16 +1,65 @@
-use std::{str::FromStr, time::Duration};
+use std::{fmt, str::FromStr, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, info, warn};
 use zbus::zvariant::Type;
 
+use crate::{
+  config::Config,
+  helper,
+  protocol::{Bus, Connections},
+  session::SessionType,
+};
+
+// the reason given to the services that ask for one
+const REASON: &str = "stay awake";
+
 #[async_trait::async_trait]
-pub trait Inhibitor {
-  // Result::Err(_) is equivalent to Ok(false)
-  async fn available(&self) -> Result<bool>;
+pub trait Inhibitor: Send + Sync {
+  async fn availability(&self) -> Availability;
+
   async fn inhibit(&mut self) -> Result<()>;
   async fn uninhibit(&mut self) -> Result<()>;
+
+  // Re-establish an inhibition that may have been released or
+  // expired on the other end. Backends that keep nothing on the
+  // other end have nothing to renew.
+  async fn refresh(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  // Resolves with the reason if the inhibition stops on its own, like
+  // when a background task dies. Never resolves for backends without
+  // anything that could stop.
+  async fn stopped(&mut self) -> anyhow::Error {
+    std::future::pending().await
+  }
+
+  // What failed to inhibit while the rest holds, for inhibitors made
+  // of several parts. None when nothing is missing.
+  fn degraded(&self) -> Option<String> {
+    None
+  }
+}
+
+// Wait for a backend's background task to end, which it only does on
+// failure
+async fn task_stopped(
+  task: &mut Option<JoinHandle<anyhow::Error>>,
+) -> anyhow::Error {
+  let Some(handle) = task else {
+    return std::future::pending().await;
+  };
+
+  let e = match handle.await {
+    Ok(e) => e,
+    Err(e) => anyhow::Error::new(e).context("task panicked"),
+  };
+  *task = None;
+  e
 }
 
 #[derive(
@@ -21,32 +70,281 @@ pub trait Inhibitor {
 pub enum InhibitMode {
   /// Inhibit sleep from xfce4-power-manager
   #[serde(alias = "xfce", alias = "xfce4")]
+  #[value(alias = "xfce", alias = "xfce4")]
   Xfce4PowerManager,
   /// Inhibit sleep from xfce4-screensaver
   Xfce4Screensaver,
-  /// Inhibit sleep with `systemd-inhibit`
+  /// Keep the GNOME screensaver from blanking and locking the screen
+  #[serde(alias = "gnome")]
+  #[value(alias = "gnome")]
+  GnomeScreensaver,
+  /// Inhibit sleep and idle through KDE Plasma's powerdevil
+  #[serde(alias = "kde", alias = "plasma")]
+  #[value(alias = "kde", alias = "plasma")]
+  KdePowerManager,
+  /// Inhibit idle through the shared org.freedesktop.ScreenSaver service
+  #[serde(alias = "freedesktop", alias = "fdo-screensaver")]
+  #[value(alias = "freedesktop", alias = "fdo-screensaver")]
+  FreedesktopScreensaver,
+  /// Inhibit sleep and idle through logind, like `systemd-inhibit`
   #[serde(alias = "systemd")]
+  #[value(alias = "systemd")]
   Logind,
   /// Reset the XScreenSaver time with `xset s reset`
   #[serde(alias = "xset")]
+  #[value(alias = "xset")]
   Xscreensaver,
+  /// Inhibit idle through the Wayland idle-inhibit protocol, for
+  /// compositors like sway and Hyprland
+  #[serde(alias = "wayland")]
+  #[value(alias = "wayland")]
+  WaylandIdleInhibit,
   /// Inhibit sleep with occasional mouse jitter
+  #[serde(alias = "mouse")]
+  #[value(alias = "mouse")]
   MouseJitter,
+  /// Inhibit sleep and idle through the XDG desktop portal, for
+  /// sandboxed setups like Flatpak
+  #[serde(alias = "xdg-portal")]
+  #[value(alias = "xdg-portal")]
+  Portal,
+  /// Pick the first of the modes above that is available, when the
+  /// daemon starts
+  Auto,
+}
+
+/// What kind of system action to hold off, independent of the
+/// mechanism used to do so
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+  /// Block system sleep
+  Sleep,
+  /// Block the screen from blanking or locking when idle
+  Idle,
+  /// Block both sleep and idle
+  Both,
+  /// Only block suspending on lid close (logind only)
+  Lid,
 }
 
-pub async fn available_modes() -> Vec<InhibitMode> {
+impl fmt::Display for Category {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Sleep => "sleep",
+      Self::Idle => "idle",
+      Self::Both => "both",
+      Self::Lid => "lid",
+    };
+    f.write_str(name)
+  }
+}
+
+/// Validate a colon-separated list of logind inhibit scopes
+pub fn parse_logind_what(s: &str) -> Result<String, String> {
+  for scope in s.split(':') {
+    if !logind::SCOPES.contains(&scope) {
+      return Err(format!(
+        "unknown logind scope '{scope}'; expected one of {}",
+        logind::SCOPES.join(", ")
+      ));
+    }
+  }
+  Ok(s.to_string())
+}
+
+/// Validate a colon-separated list of portal inhibit flags
+pub fn parse_portal_flags(s: &str) -> Result<String, String> {
+  for flag in s.split(':') {
+    if portal::flag(flag).is_none() {
+      let names: Vec<_> = portal::FLAGS.iter().map(|(n, _)| *n).collect();
+      return Err(format!(
+        "unknown portal flag '{flag}'; expected one of {}",
+        names.join(", ")
+      ));
+    }
+  }
+  Ok(s.to_string())
+}
+
+/// Whether a mode can be used, and why not if it can't
+#[derive(Debug, Clone, PartialEq)]
+pub enum Availability {
+  Available,
+  Unavailable(String),
+}
+
+impl Availability {
+  pub fn is_available(&self) -> bool {
+    *self == Self::Available
+  }
+
+  pub fn reason(&self) -> Option<&str> {
+    match self {
+      Self::Available => None,
+      Self::Unavailable(reason) => Some(reason),
+    }
+  }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ModeAvailability {
+  pub mode: InhibitMode,
+  pub available: bool,
+  // why the mode is unavailable
+  pub reason: Option<String>,
+  // what its inhibitors show up as, see `InhibitMode::footprint`
+  pub footprint: Option<String>,
+}
+
+/// Probe every mode for availability on the system
+pub async fn probe_modes(config: &Config) -> Vec<ModeAvailability> {
+  let connections = Connections::default();
   let mut modes = Vec::new();
-  for mode in InhibitMode::value_variants() {
-    let inhibitor = from_mode(*mode).await;
+  let variants = InhibitMode::value_variants().iter();
+  for mode in variants.filter(|m| **m != InhibitMode::Auto) {
+    let availability = match from_mode(*mode, config, &connections).await {
+      Ok(inhibitor) => inhibitor.availability().await,
+      Err(e) => Availability::Unavailable(e.to_string()),
+    };
+
+    modes.push(ModeAvailability {
+      mode: *mode,
+      available: availability.is_available(),
+      reason: availability.reason().map(str::to_string),
+      footprint: mode.footprint(config),
+    });
+  }
+
+  modes
+}
 
-    if let Ok(inhibitor) = inhibitor {
-      if inhibitor.available().await.unwrap_or(false) {
-        modes.push(*mode);
+/// Replace `auto` among the modes with the first mode that is
+/// available and has an effect in this session
+pub async fn resolve_auto(
+  modes: &[InhibitMode],
+  config: &Config,
+) -> Result<Vec<InhibitMode>> {
+  if !modes.contains(&InhibitMode::Auto) {
+    return Ok(modes.to_vec());
+  }
+
+  let session = SessionType::detect();
+  let probed = probe_modes(config).await;
+  let Some(found) = probed
+    .iter()
+    .find(|m| m.available && m.mode.works_in(session))
+  else {
+    let tried: Vec<_> = probed
+      .iter()
+      .map(|m| {
+        let reason = m.reason.as_deref().unwrap_or("no effect in this session");
+        format!("{} ({reason})", m.mode)
+      })
+      .collect();
+    anyhow::bail!("no inhibit mode is available, tried {}", tried.join(", "));
+  };
+  info!("Picked inhibit mode {} automatically", found.mode);
+
+  let mut resolved = Vec::new();
+  for &mode in modes {
+    let mode = match mode {
+      InhibitMode::Auto => found.mode,
+      mode => mode,
+    };
+    if !resolved.contains(&mode) {
+      resolved.push(mode);
+    }
+  }
+  Ok(resolved)
+}
+
+impl InhibitMode {
+  /// The canonical kebab-case name, as accepted by `FromStr`
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Xfce4PowerManager => "xfce4-power-manager",
+      Self::Xfce4Screensaver => "xfce4-screensaver",
+      Self::GnomeScreensaver => "gnome-screensaver",
+      Self::KdePowerManager => "kde-power-manager",
+      Self::FreedesktopScreensaver => "freedesktop-screensaver",
+      Self::Logind => "logind",
+      Self::Xscreensaver => "xscreensaver",
+      Self::WaylandIdleInhibit => "wayland-idle-inhibit",
+      Self::MouseJitter => "mouse-jitter",
+      Self::Portal => "portal",
+      Self::Auto => "auto",
+    }
+  }
+
+  /// One-line description, taken from the doc comment on the variant
+  pub fn description(&self) -> String {
+    self
+      .to_possible_value()
+      .and_then(|v| v.get_help().map(|help| help.to_string()))
+      .unwrap_or_default()
+  }
+
+  /// How the mode's inhibitors identify themselves to the service
+  /// holding them, e.g. in `systemd-inhibit --list`. None for modes
+  /// that register nothing anywhere.
+  pub fn footprint(&self, config: &Config) -> Option<String> {
+    let app = &config.app_id;
+    match self {
+      Self::Logind => {
+        let what = logind::what(config);
+        Some(format!("who={app}, what={what}, why={}", logind::WHY))
+      }
+      Self::Xfce4PowerManager
+      | Self::Xfce4Screensaver
+      | Self::GnomeScreensaver
+      | Self::KdePowerManager
+      | Self::FreedesktopScreensaver => {
+        Some(format!("app={app}, reason={REASON}"))
       }
+      // the portal finds out who is asking by itself
+      Self::Portal => Some(format!("reason={REASON}")),
+      Self::Xscreensaver
+      | Self::WaylandIdleInhibit
+      | Self::MouseJitter
+      | Self::Auto => None,
     }
   }
 
-  modes
+  /// Whether the mode is able to inhibit the given category
+  pub fn supports(&self, category: Category) -> bool {
+    use Category::*;
+
+    match self {
+      Self::Logind | Self::Auto => true,
+      Self::Xfce4PowerManager => category == Sleep,
+      Self::Xfce4Screensaver
+      | Self::GnomeScreensaver
+      | Self::FreedesktopScreensaver
+      | Self::Xscreensaver
+      | Self::WaylandIdleInhibit => category == Idle,
+      // simulated activity keeps both idle timers from firing
+      Self::MouseJitter | Self::Portal | Self::KdePowerManager => {
+        category != Lid
+      }
+    }
+  }
+
+  /// Whether the mode can have any effect in the given session
+  pub fn works_in(&self, session: SessionType) -> bool {
+    match self {
+      // both rely on talking to an X server
+      Self::Xscreensaver | Self::MouseJitter => session == SessionType::X11,
+      Self::WaylandIdleInhibit => session == SessionType::Wayland,
+      _ => true,
+    }
+  }
+}
+
+impl fmt::Display for InhibitMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
 }
 
 impl FromStr for InhibitMode {
@@ -56,55 +354,490 @@ impl FromStr for InhibitMode {
     match s {
       "xscreensaver" => Ok(Self::Xscreensaver),
       "xset" => Ok(Self::Xscreensaver),
+      "wayland-idle-inhibit" => Ok(Self::WaylandIdleInhibit),
+      "wayland" => Ok(Self::WaylandIdleInhibit),
       "logind" => Ok(Self::Logind),
       "xfce4-power-manager" => Ok(Self::Xfce4PowerManager),
       "xfce" => Ok(Self::Xfce4PowerManager),
       "xfce4" => Ok(Self::Xfce4PowerManager),
       "xfce4-screensaver" => Ok(Self::Xfce4Screensaver),
+      "gnome-screensaver" => Ok(Self::GnomeScreensaver),
+      "gnome" => Ok(Self::GnomeScreensaver),
+      "kde-power-manager" => Ok(Self::KdePowerManager),
+      "kde" => Ok(Self::KdePowerManager),
+      "plasma" => Ok(Self::KdePowerManager),
+      "freedesktop-screensaver" => Ok(Self::FreedesktopScreensaver),
+      "freedesktop" => Ok(Self::FreedesktopScreensaver),
+      "fdo-screensaver" => Ok(Self::FreedesktopScreensaver),
       "mouse-jitter" => Ok(Self::MouseJitter),
       "mouse" => Ok(Self::MouseJitter),
+      "portal" => Ok(Self::Portal),
+      "xdg-portal" => Ok(Self::Portal),
+      "auto" => Ok(Self::Auto),
       _ => Err(anyhow::anyhow!("unknown mechanism: {}", s)),
     }
   }
 }
 
-pub async fn from_mode(mode: InhibitMode) -> Result<Box<dyn Inhibitor>> {
+pub async fn from_mode(
+  mode: InhibitMode,
+  config: &Config,
+  connections: &Connections,
+) -> Result<Box<dyn Inhibitor>> {
   use InhibitMode::*;
 
   fn ok(inhibitor: impl Inhibitor + 'static) -> Result<Box<dyn Inhibitor>> {
     Ok(Box::new(inhibitor))
   }
 
+  if let Some(category) = config.category {
+    if mode == Logind && config.logind_what.is_some() {
+      warn!("--logind-what overrides --category {category}");
+    } else if mode == Portal && config.portal_flags.is_some() {
+      warn!("--portal-flags overrides --category {category}");
+    } else if !mode.supports(category) {
+      warn!("Mode {mode} cannot inhibit {category} only");
+    }
+  }
+
   match mode {
     Xscreensaver => {
-      ok(xscreensaver::XScreensaver::new(Duration::from_secs(60)))
+      let interval = config.xscreensaver_interval.max(MIN_INTERVAL);
+      ok(xscreensaver::XScreensaver::new(interval))
     }
     Logind => {
-      let conn = zbus::Connection::system().await?;
-      ok(logind::LogindInhibit::new(conn))
+      let conn = connections.get(Bus::System).await?;
+      let what = logind::what(config);
+      ok(logind::LogindInhibit::new(conn, &config.app_id, what))
+    }
+    Xfce4PowerManager
+    | Xfce4Screensaver
+    | GnomeScreensaver
+    | FreedesktopScreensaver => {
+      let conn = connections.get(Bus::Session).await?;
+      let service = match mode {
+        Xfce4PowerManager => &cookie::XFCE_POWER_MANAGER,
+        Xfce4Screensaver => &cookie::XFCE_SCREEN_SAVER,
+        GnomeScreensaver => &cookie::GNOME_SCREEN_SAVER,
+        _ => &cookie::FREEDESKTOP_SCREEN_SAVER,
+      };
+      ok(cookie::CookieInhibit::new(conn, service, &config.app_id))
+    }
+    KdePowerManager => {
+      let conn = connections.get(Bus::Session).await?;
+      let types = cookie::kde_types(config.category);
+      let service = &cookie::KDE_POLICY_AGENT;
+      ok(
+        cookie::CookieInhibit::new(conn, service, &config.app_id)
+          .with_types(types),
+      )
     }
-    Xfce4PowerManager => {
-      let conn = zbus::Connection::session().await?;
-      ok(xfce_power_manager::XfcePowerManager::new(conn))
+    WaylandIdleInhibit => ok(wayland_idle_inhibit::WaylandIdleInhibit::new()),
+    MouseJitter => {
+      let interval = config.mouse_jitter_interval.max(MIN_INTERVAL);
+      ok(mouse_jitter::MouseJitter::new(interval))
     }
-    Xfce4Screensaver => {
-      let conn = zbus::Connection::session().await?;
-      ok(xfce_screen_saver::XfceScreenSaver::new(conn))
+    Portal => {
+      let conn = connections.get(Bus::Session).await?;
+      ok(portal::Portal::new(conn, portal::flags(config)))
     }
-    MouseJitter => ok(mouse_jitter::MouseJitter::new(Duration::from_secs(60))),
+    // see `resolve_auto`
+    Auto => anyhow::bail!("auto is only resolved when the daemon starts"),
   }
 }
 
-mod xscreensaver {
-  use std::time::Duration;
+/// Build the inhibitor for the configured modes, holding all of them
+/// together when there is more than one, or only the first that works
+/// with --fallback
+pub async fn from_modes(
+  modes: &[InhibitMode],
+  config: &Config,
+  connections: &Connections,
+) -> Result<Box<dyn Inhibitor>> {
+  for &mode in modes {
+    check_interval(mode, config).await;
+  }
+
+  if let [mode] = modes {
+    let inhibitor = from_mode(*mode, config, connections).await?;
+    warn_unavailable(*mode, inhibitor.as_ref()).await;
+    return Ok(inhibitor);
+  }
+
+  let mut members = Vec::new();
+  for &mode in modes {
+    match from_mode(mode, config, connections).await {
+      Ok(inhibitor) => {
+        warn_unavailable(mode, inhibitor.as_ref()).await;
+        members.push((mode, inhibitor));
+      }
+      Err(e) => warn!("Skipping mode {mode}: {e:#}"),
+    }
+  }
+
+  if members.is_empty() {
+    anyhow::bail!("none of the modes could be set up");
+  }
+  if config.fallback {
+    return Ok(Box::new(fallback::Fallback::new(members)));
+  }
+  Ok(Box::new(composite::Composite::new(members)))
+}
+
+/// Try a mode out with a brief inhibit and release
+pub async fn check_mode(
+  mode: InhibitMode,
+  config: &Config,
+  connections: &Connections,
+) -> Result<()> {
+  let mut inhibitor = from_mode(mode, config, connections).await?;
+  if let Availability::Unavailable(reason) = inhibitor.availability().await {
+    anyhow::bail!("not available: {reason}");
+  }
+  inhibitor.inhibit().await.context("failed to inhibit")?;
+  inhibitor.uninhibit().await.context("failed to release")?;
+  Ok(())
+}
+
+// the shortest interval the periodic modes act at
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Warn about an interval a periodic mode can't do its job at. Only
+// done for the modes the daemon runs, asking the X server would stall
+// probing them all on other sessions.
+async fn check_interval(mode: InhibitMode, config: &Config) {
+  use helper::format_duration;
+
+  let interval = match mode {
+    InhibitMode::Xscreensaver => config.xscreensaver_interval,
+    InhibitMode::MouseJitter => config.mouse_jitter_interval,
+    _ => return,
+  };
+
+  if interval < MIN_INTERVAL {
+    warn!(
+      "--{mode}-interval {} is too short, using {}",
+      format_duration(interval),
+      format_duration(MIN_INTERVAL)
+    );
+    return;
+  }
+
+  let timeout = tokio::task::spawn_blocking(x11_screensaver_timeout).await;
+  if let Ok(Some(timeout)) = timeout {
+    if interval >= timeout {
+      warn!(
+        "--{mode}-interval {} is no shorter than the screen saver \
+         timeout of {}, the screen will still blank",
+        format_duration(interval),
+        format_duration(timeout)
+      );
+    }
+  }
+}
+
+// The X server's screen saver timeout, when on X11 and it's enabled
+fn x11_screensaver_timeout() -> Option<Duration> {
+  use x11rb::protocol::xproto::ConnectionExt as _;
+
+  let (conn, _) = x11rb::connect(None).ok()?;
+  let reply = conn.get_screen_saver().ok()?.reply().ok()?;
+  (reply.timeout > 0).then(|| Duration::from_secs(reply.timeout.into()))
+}
+
+// unavailable modes are kept, their peer may still show up later
+async fn warn_unavailable(mode: InhibitMode, inhibitor: &dyn Inhibitor) {
+  if let Availability::Unavailable(reason) = inhibitor.availability().await {
+    warn!(
+      "Mode {mode} is not available ({reason}), see `vigilare list-modes --all`"
+    );
+  }
+}
+
+// D-Bus backends are available when their peer answers on the bus
+async fn peer_availability(
+  proxy: zbus::Result<zbus::Proxy<'_>>,
+  name: &str,
+) -> Availability {
+  let proxy = match proxy {
+    Ok(proxy) => proxy,
+    Err(e) => return Availability::Unavailable(e.to_string()),
+  };
+
+  match proxy.introspect().await {
+    Ok(_) => Availability::Available,
+    Err(_) => Availability::Unavailable(format!("{name} is not running")),
+  }
+}
+
+mod composite {
+  use futures::future;
+
+  use super::*;
+
+  /// Several inhibitors held together, e.g. logind for sleep and the
+  /// screensaver for blanking.
+  ///
+  /// Holding some of the modes is better than none:
+  ///
+  /// - `inhibit` succeeds as long as one member does. The members that
+  ///   failed are left out, logged and reported by `degraded`, and
+  ///   are tried again on every `refresh`. It fails only if every
+  ///   member does, leaving nothing held.
+  /// - `uninhibit` releases every member, even after one fails, and
+  ///   reports the failures together.
+  /// - `refresh` renews the members that are held and tries the
+  ///   others again. A member that fails to renew is left out like
+  ///   one that failed to inhibit; it fails only once no member holds.
+  pub struct Composite {
+    members: Vec<Member>,
+  }
+
+  struct Member {
+    mode: InhibitMode,
+    inhibitor: Box<dyn Inhibitor>,
+    // why the last attempt to inhibit failed, cleared on success
+    failure: Option<String>,
+  }
+
+  impl Composite {
+    pub fn new(members: Vec<(InhibitMode, Box<dyn Inhibitor>)>) -> Self {
+      let members = members
+        .into_iter()
+        .map(|(mode, inhibitor)| Member {
+          mode,
+          inhibitor,
+          failure: None,
+        })
+        .collect();
+      Self { members }
+    }
+  }
+
+  // every member gets its turn even if an earlier one fails, and the
+  // failures are reported together
+  fn collect(failures: Vec<String>) -> Result<()> {
+    if failures.is_empty() {
+      return Ok(());
+    }
+    Err(anyhow::anyhow!(failures.join("; ")))
+  }
 
+  #[async_trait::async_trait]
+  impl Inhibitor for Composite {
+    // usable as long as one member is, see `inhibit`
+    async fn availability(&self) -> Availability {
+      let mut reasons = Vec::new();
+      for member in &self.members {
+        match member.inhibitor.availability().await {
+          Availability::Available => return Availability::Available,
+          Availability::Unavailable(reason) => {
+            reasons.push(format!("{}: {reason}", member.mode))
+          }
+        }
+      }
+      Availability::Unavailable(reasons.join("; "))
+    }
+
+    async fn inhibit(&mut self) -> Result<()> {
+      for member in &mut self.members {
+        member.failure = match member.inhibitor.inhibit().await {
+          Ok(()) => None,
+          Err(e) => Some(format!("{e:#}")),
+        };
+      }
+
+      if self.members.iter().all(|m| m.failure.is_some()) {
+        // nothing is held, so nothing is left out either
+        let failures = self.members.iter_mut().filter_map(|m| {
+          let failure = m.failure.take()?;
+          Some(format!("{}: {failure}", m.mode))
+        });
+        return collect(failures.collect());
+      }
+
+      if let Some(missing) = self.degraded() {
+        warn!("Failed to inhibit with {missing}, going on without it");
+      }
+      Ok(())
+    }
+
+    async fn uninhibit(&mut self) -> Result<()> {
+      let mut failures = Vec::new();
+      for member in &mut self.members {
+        member.failure = None;
+        if let Err(e) = member.inhibitor.uninhibit().await {
+          failures.push(format!("{}: {e:#}", member.mode));
+        }
+      }
+      collect(failures)
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      for member in &mut self.members {
+        if member.failure.is_none() {
+          if let Err(e) = member.inhibitor.refresh().await {
+            warn!("Failed to renew {}, going on without it", member.mode);
+            member.failure = Some(format!("{e:#}"));
+          }
+          continue;
+        }
+
+        match member.inhibitor.inhibit().await {
+          Ok(()) => {
+            info!("Inhibiting with {} again", member.mode);
+            member.failure = None;
+          }
+          Err(e) => {
+            debug!("Still failing to inhibit with {}: {e:#}", member.mode);
+            member.failure = Some(format!("{e:#}"));
+          }
+        }
+      }
+
+      match self.degraded() {
+        Some(missing) if self.members.iter().all(|m| m.failure.is_some()) => {
+          Err(anyhow::anyhow!(missing))
+        }
+        _ => Ok(()),
+      }
+    }
+
+    async fn stopped(&mut self) -> anyhow::Error {
+      let stopped = self.members.iter_mut().map(|member| {
+        let mode = member.mode;
+        let inhibitor = &mut member.inhibitor;
+        Box::pin(async move { inhibitor.stopped().await.context(mode) })
+      });
+      let (e, _, _) = future::select_all(stopped).await;
+      e
+    }
+
+    fn degraded(&self) -> Option<String> {
+      let failures: Vec<_> = self
+        .members
+        .iter()
+        .filter_map(|m| Some(format!("{}: {}", m.mode, m.failure.as_ref()?)))
+        .collect();
+      (!failures.is_empty()).then(|| failures.join("; "))
+    }
+  }
+}
+
+mod fallback {
+  use super::*;
+
+  /// Several modes tried in order, holding only the first that
+  /// inhibits. Unavailable modes are passed over, since the periodic
+  /// ones only find out they can't work after "inhibiting". The choice
+  /// sticks: later vigils try it first, and only go down the list
+  /// again once it fails.
+  pub struct Fallback {
+    members: Vec<(InhibitMode, Box<dyn Inhibitor>)>,
+    // the member inhibiting, or the one that last did
+    chosen: Option<usize>,
+    held: bool,
+  }
+
+  impl Fallback {
+    pub fn new(members: Vec<(InhibitMode, Box<dyn Inhibitor>)>) -> Self {
+      Self {
+        members,
+        chosen: None,
+        held: false,
+      }
+    }
+  }
+
+  #[async_trait::async_trait]
+  impl Inhibitor for Fallback {
+    async fn availability(&self) -> Availability {
+      let mut reasons = Vec::new();
+      for (mode, inhibitor) in &self.members {
+        match inhibitor.availability().await {
+          Availability::Available => return Availability::Available,
+          Availability::Unavailable(reason) => {
+            reasons.push(format!("{mode}: {reason}"))
+          }
+        }
+      }
+      Availability::Unavailable(reasons.join("; "))
+    }
+
+    async fn inhibit(&mut self) -> Result<()> {
+      if self.held {
+        return Ok(());
+      }
+
+      let chosen = self.chosen;
+      let order = chosen
+        .into_iter()
+        .chain((0..self.members.len()).filter(|&i| Some(i) != chosen));
+
+      let mut failures = Vec::new();
+      for i in order {
+        let (mode, inhibitor) = &mut self.members[i];
+        if let Availability::Unavailable(reason) =
+          inhibitor.availability().await
+        {
+          debug!("Passing over mode {mode}: {reason}");
+          failures.push(format!("{mode}: {reason}"));
+          continue;
+        }
+
+        match inhibitor.inhibit().await {
+          Ok(()) => {
+            if chosen != Some(i) {
+              info!("Settled on mode {mode}");
+            }
+            self.chosen = Some(i);
+            self.held = true;
+            return Ok(());
+          }
+          Err(e) => {
+            warn!("Mode {mode} failed to inhibit: {e:#}");
+            failures.push(format!("{mode}: {e:#}"));
+          }
+        }
+      }
+      Err(anyhow::anyhow!(failures.join("; ")))
+    }
+
+    async fn uninhibit(&mut self) -> Result<()> {
+      let Some(i) = self.chosen.filter(|_| self.held) else {
+        return Ok(());
+      };
+      self.held = false;
+      self.members[i].1.uninhibit().await
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      match self.chosen.filter(|_| self.held) {
+        Some(i) => self.members[i].1.refresh().await,
+        None => Ok(()),
+      }
+    }
+
+    async fn stopped(&mut self) -> anyhow::Error {
+      let Some(i) = self.chosen.filter(|_| self.held) else {
+        return std::future::pending().await;
+      };
+      let (mode, inhibitor) = &mut self.members[i];
+      let e = inhibitor.stopped().await.context(*mode);
+      self.held = false;
+      e
+    }
+  }
+}
+
+mod xscreensaver {
   use tokio::process::Command;
 
   use super::*;
 
   pub struct XScreensaver {
     interval: Duration,
-    task: Option<tokio::task::JoinHandle<()>>,
+    task: Option<JoinHandle<anyhow::Error>>,
   }
 
   impl XScreensaver {
@@ -118,11 +851,13 @@ mod xscreensaver {
 
   #[async_trait::async_trait]
   impl Inhibitor for XScreensaver {
-    async fn available(&self) -> Result<bool> {
+    async fn availability(&self) -> Availability {
       // available if xset binary is found in PATH
-      let output = Command::new("which").arg("xset").output().await?;
-
-      Ok(output.status.success())
+      match Command::new("which").arg("xset").output().await {
+        Ok(output) if output.status.success() => Availability::Available,
+        Ok(_) => Availability::Unavailable("xset not found in PATH".into()),
+        Err(e) => Availability::Unavailable(format!("cannot run which: {e}")),
+      }
     }
 
     async fn inhibit(&mut self) -> Result<()> {
@@ -134,12 +869,10 @@ mod xscreensaver {
       let task = tokio::spawn(async move {
         loop {
           tokio::time::sleep(reset_duration).await;
-          Command::new("xset")
-            .arg("s")
-            .arg("reset")
-            .output()
-            .await
-            .expect("failed to run xset s reset");
+          let reset = Command::new("xset").arg("s").arg("reset").output();
+          if let Err(e) = reset.await {
+            return anyhow::Error::new(e).context("failed to run xset s reset");
+          }
         }
       });
       self.task = Some(task);
@@ -152,9 +885,15 @@ mod xscreensaver {
       }
       Ok(())
     }
+
+    async fn stopped(&mut self) -> anyhow::Error {
+      task_stopped(&mut self.task).await
+    }
   }
 }
 
+pub use logind::{list_inhibitors, watch_lock, watch_resume};
+
 mod logind {
   use zbus::Connection;
 
@@ -174,24 +913,155 @@ mod logind {
       why: &str,
       mode: &str,
     ) -> zbus::Result<zbus::zvariant::OwnedFd>;
+
+    fn list_inhibitors(&self) -> zbus::Result<Vec<InhibitorLock>>;
+
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+  }
+
+  // the session we run in, `auto` resolves to it
+  #[zbus::proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1/session/auto"
+  )]
+  trait LogindSession {
+    #[zbus(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+  }
+
+  /// Watch whether our session is locked, as screen lockers report to
+  /// logind. The receiver gets the current state first, then every
+  /// change.
+  pub async fn watch_lock(
+    connections: &Connections,
+  ) -> Result<mpsc::Receiver<bool>> {
+    use futures::StreamExt as _;
+
+    let conn = connections.try_get(Bus::System).await?;
+    let session = LogindSessionProxy::new(&conn).await?;
+    let mut changes = session.receive_locked_hint_changed().await;
+
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+      while let Some(change) = changes.next().await {
+        let Ok(locked) = change.get().await else {
+          continue;
+        };
+        if sender.send(locked).await.is_err() {
+          break;
+        }
+      }
+    });
+
+    Ok(receiver)
   }
 
+  /// An inhibitor lock held through logind by any process
+  #[derive(Debug, Clone, serde::Deserialize, Type)]
+  pub struct InhibitorLock {
+    pub what: String,
+    pub who: String,
+    pub why: String,
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+  }
+
+  /// All inhibitor locks logind knows about, held by anyone
+  pub async fn list_inhibitors() -> Result<Vec<InhibitorLock>> {
+    let conn = Connection::system()
+      .await
+      .context("system bus not reachable")?;
+    let manager = LogindManagerProxy::new(&conn).await?;
+    Ok(manager.list_inhibitors().await?)
+  }
+
+  /// Watch for the system waking up from sleep. The receiver gets a
+  /// message on every resume.
+  pub async fn watch_resume(
+    connections: &Connections,
+  ) -> Result<mpsc::Receiver<()>> {
+    use futures::StreamExt as _;
+
+    let conn = connections.try_get(Bus::System).await?;
+    let manager = LogindManagerProxy::new(&conn).await?;
+    let mut signals = manager.receive_prepare_for_sleep().await?;
+
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+      while let Some(signal) = signals.next().await {
+        // the same signal announces going to sleep with `true`
+        let resumed = signal.args().is_ok_and(|args| !args.start);
+        if resumed && sender.send(()).await.is_err() {
+          break;
+        }
+      }
+    });
+
+    Ok(receiver)
+  }
+
+  /// Scopes logind accepts in the `what` argument of `Inhibit`
+  pub const SCOPES: &[&str] = &[
+    "shutdown",
+    "sleep",
+    "idle",
+    "handle-power-key",
+    "handle-suspend-key",
+    "handle-hibernate-key",
+    "handle-lid-switch",
+  ];
+
+  /// The `what` argument that covers a category
+  pub fn what_for(category: Option<Category>) -> &'static str {
+    match category {
+      Some(Category::Sleep) => "sleep",
+      Some(Category::Idle) => "idle",
+      // blocking sleep alone still lets the screen lock
+      None | Some(Category::Both) => "sleep:idle",
+      Some(Category::Lid) => "handle-lid-switch",
+    }
+  }
+
+  /// The `what` argument for the configuration, where --logind-what
+  /// wins over --category
+  pub fn what(config: &Config) -> String {
+    match &config.logind_what {
+      Some(what) => what.clone(),
+      None => what_for(config.category).to_string(),
+    }
+  }
+
+  // the reason logind shows for our locks
+  pub const WHY: &str = "user request";
+
   pub struct LogindInhibit {
     conn: Connection,
+    who: String,
+    // all scopes are held by a single fd
+    what: String,
     fd: Option<zbus::zvariant::OwnedFd>,
   }
 
   impl LogindInhibit {
-    pub fn new(conn: Connection) -> Self {
-      Self { conn, fd: None }
+    pub fn new(conn: Connection, who: &str, what: String) -> Self {
+      Self {
+        conn,
+        who: who.to_string(),
+        what,
+        fd: None,
+      }
     }
   }
 
   #[async_trait::async_trait]
   impl Inhibitor for LogindInhibit {
-    async fn available(&self) -> Result<bool> {
-      let proxy = LogindManagerProxy::new(&self.conn).await?;
-      Ok(proxy.0.introspect().await.is_ok())
+    async fn availability(&self) -> Availability {
+      let proxy = LogindManagerProxy::new(&self.conn).await;
+      peer_availability(proxy.map(LogindManagerProxy::into_inner), "logind")
+        .await
     }
 
     async fn inhibit(&mut self) -> Result<()> {
@@ -201,9 +1071,7 @@ mod logind {
 
       let manager = LogindManagerProxy::new(&self.conn).await?;
 
-      let fd = manager
-        .inhibit("sleep", "vigilare", "user request", "block")
-        .await?;
+      let fd = manager.inhibit(&self.what, &self.who, WHY, "block").await?;
 
       self.fd = Some(fd);
       Ok(())
@@ -214,41 +1082,165 @@ mod logind {
       self.fd.take();
       Ok(())
     }
+
+    async fn refresh(&mut self) -> Result<()> {
+      if self.fd.is_none() {
+        return Ok(());
+      }
+
+      // take the new lock before the old one goes, so there's no gap
+      let manager = LogindManagerProxy::new(&self.conn).await?;
+      let fd = manager.inhibit(&self.what, &self.who, WHY, "block").await?;
+      self.fd = Some(fd);
+      Ok(())
+    }
   }
 }
 
-mod xfce_power_manager {
-  use zbus::Connection;
+mod cookie {
+  use zbus::{Connection, Proxy};
 
   use super::*;
 
-  #[zbus::proxy(
-    interface = "org.freedesktop.PowerManagement.Inhibit",
-    default_service = "org.xfce.PowerManager",
-    default_path = "/org/freedesktop/PowerManagement/Inhibit"
-  )]
-  trait XfcePowerManager {
-    fn inhibit(&self, application: &str, reason: &str) -> zbus::Result<u32>;
-    #[zbus(name = "UnInhibit")]
-    fn uninhibit(&self, cookie: u32) -> zbus::Result<()>;
+  /// Where a service that hands out a cookie on inhibiting, to be
+  /// given back on release, lives on the bus
+  pub struct Service {
+    // the program serving it, for telling that it's not running
+    program: &'static str,
+    destination: &'static str,
+    path: &'static str,
+    interface: &'static str,
+    inhibit: &'static str,
+    uninhibit: &'static str,
   }
 
-  pub struct XfcePowerManager {
+  pub const XFCE_POWER_MANAGER: Service = Service {
+    program: "xfce4-power-manager",
+    destination: "org.xfce.PowerManager",
+    path: "/org/freedesktop/PowerManagement/Inhibit",
+    interface: "org.freedesktop.PowerManagement.Inhibit",
+    inhibit: "Inhibit",
+    uninhibit: "UnInhibit",
+  };
+
+  pub const XFCE_SCREEN_SAVER: Service = Service {
+    program: "xfce4-screensaver",
+    destination: "org.xfce.ScreenSaver",
+    path: "/",
+    interface: "org.xfce.ScreenSaver",
+    inhibit: "Inhibit",
+    uninhibit: "UnInhibit",
+  };
+
+  pub const GNOME_SCREEN_SAVER: Service = Service {
+    program: "gnome-screensaver",
+    destination: "org.gnome.ScreenSaver",
+    path: "/org/gnome/ScreenSaver",
+    interface: "org.gnome.ScreenSaver",
+    inhibit: "Inhibit",
+    uninhibit: "UnInhibit",
+  };
+
+  // the interface many desktops share, e.g. Cinnamon, MATE and LXQt
+  pub const FREEDESKTOP_SCREEN_SAVER: Service = Service {
+    program: "org.freedesktop.ScreenSaver",
+    destination: "org.freedesktop.ScreenSaver",
+    path: "/org/freedesktop/ScreenSaver",
+    interface: "org.freedesktop.ScreenSaver",
+    inhibit: "Inhibit",
+    uninhibit: "UnInhibit",
+  };
+
+  // takes the policies to hold off ahead of the application, see
+  // `CookieInhibit::with_types`
+  pub const KDE_POLICY_AGENT: Service = Service {
+    program: "powerdevil",
+    destination: "org.kde.Solid.PowerManagement",
+    path: "/org/kde/Solid/PowerManagement/PolicyAgent",
+    interface: "org.kde.Solid.PowerManagement.PolicyAgent",
+    inhibit: "AddInhibition",
+    uninhibit: "ReleaseInhibition",
+  };
+
+  // the policies an inhibition holds off, from powerdevil's
+  // `PolicyAgent::RequiredPolicy`
+  const INTERRUPT_SESSION: u32 = 1;
+  const CHANGE_SCREEN_SETTINGS: u32 = 4;
+
+  /// The types argument of KDE's `AddInhibition` that covers a
+  /// category
+  pub fn kde_types(category: Option<Category>) -> u32 {
+    match category {
+      Some(Category::Sleep) | Some(Category::Lid) => INTERRUPT_SESSION,
+      Some(Category::Idle) => CHANGE_SCREEN_SETTINGS,
+      None | Some(Category::Both) => INTERRUPT_SESSION | CHANGE_SCREEN_SETTINGS,
+    }
+  }
+
+  pub struct CookieInhibit {
     conn: Connection,
+    service: &'static Service,
+    application: String,
+    types: Option<u32>,
     cookie: Option<u32>,
   }
 
-  impl XfcePowerManager {
-    pub fn new(conn: Connection) -> Self {
-      Self { conn, cookie: None }
+  impl CookieInhibit {
+    pub fn new(
+      conn: Connection,
+      service: &'static Service,
+      application: &str,
+    ) -> Self {
+      Self {
+        conn,
+        service,
+        application: application.to_string(),
+        types: None,
+        cookie: None,
+      }
+    }
+
+    /// Pass the policies to hold off ahead of the application, as
+    /// KDE's `AddInhibition` takes them
+    pub fn with_types(self, types: u32) -> Self {
+      Self {
+        types: Some(types),
+        ..self
+      }
+    }
+
+    async fn proxy(&self) -> zbus::Result<Proxy<'static>> {
+      let Service {
+        destination,
+        path,
+        interface,
+        ..
+      } = self.service;
+      Proxy::new(&self.conn, *destination, *path, *interface).await
+    }
+
+    async fn add(&self, proxy: &Proxy<'_>) -> zbus::Result<u32> {
+      let method = self.service.inhibit;
+      let application = self.application.as_str();
+      match self.types {
+        Some(types) => proxy.call(method, &(types, application, REASON)).await,
+        None => proxy.call(method, &(application, REASON)).await,
+      }
+    }
+
+    async fn release(
+      &self,
+      proxy: &Proxy<'_>,
+      cookie: u32,
+    ) -> zbus::Result<()> {
+      proxy.call(self.service.uninhibit, &(cookie,)).await
     }
   }
 
   #[async_trait::async_trait]
-  impl Inhibitor for XfcePowerManager {
-    async fn available(&self) -> Result<bool> {
-      let proxy = XfcePowerManagerProxy::new(&self.conn).await?;
-      Ok(proxy.0.introspect().await.is_ok())
+  impl Inhibitor for CookieInhibit {
+    async fn availability(&self) -> Availability {
+      peer_availability(self.proxy().await, self.service.program).await
     }
 
     async fn inhibit(&mut self) -> Result<()> {
@@ -256,87 +1248,396 @@ mod xfce_power_manager {
         return Ok(());
       }
 
-      let manager = XfcePowerManagerProxy::new(&self.conn).await?;
-      let cookie = manager.inhibit("vigilare", "stay awake").await?;
-      self.cookie = Some(cookie);
+      let proxy = self.proxy().await?;
+      self.cookie = Some(self.add(&proxy).await?);
       Ok(())
     }
 
     async fn uninhibit(&mut self) -> Result<()> {
       if let Some(cookie) = self.cookie.take() {
-        let manager = XfcePowerManagerProxy::new(&self.conn).await?;
-        manager.uninhibit(cookie).await?;
+        let proxy = self.proxy().await?;
+        self.release(&proxy, cookie).await?;
+      }
+      Ok(())
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      let Some(old) = self.cookie else {
+        return Ok(());
+      };
+
+      let proxy = self.proxy().await?;
+      self.cookie = Some(self.add(&proxy).await?);
+
+      // the old cookie may be what went stale
+      if let Err(e) = self.release(&proxy, old).await {
+        debug!("Failed to release old cookie {old}: {e}");
       }
       Ok(())
     }
   }
 }
 
-mod xfce_screen_saver {
-  use zbus::Connection;
+mod portal {
+  use std::collections::HashMap;
+
+  use zbus::{
+    zvariant::{OwnedObjectPath, Value},
+    Connection,
+  };
 
   use super::*;
 
   #[zbus::proxy(
-    interface = "org.xfce.ScreenSaver",
-    default_service = "org.xfce.ScreenSaver",
-    default_path = "/"
+    interface = "org.freedesktop.portal.Inhibit",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+  )]
+  trait PortalInhibit {
+    fn inhibit(
+      &self,
+      window: &str,
+      flags: u32,
+      options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+  }
+
+  // the inhibition lasts until its request object is closed
+  #[zbus::proxy(
+    interface = "org.freedesktop.portal.Request",
+    default_service = "org.freedesktop.portal.Desktop"
   )]
-  trait XfceScreenSaver {
-    fn inhibit(&self, application: &str, reason: &str) -> zbus::Result<u32>;
-    #[zbus(name = "UnInhibit")]
-    fn uninhibit(&self, cookie: u32) -> zbus::Result<()>;
+  trait PortalRequest {
+    fn close(&self) -> zbus::Result<()>;
   }
 
-  pub struct XfceScreenSaver {
+  const SUSPEND: u32 = 4;
+  const IDLE: u32 = 8;
+
+  // the bits of the flags argument, by the names --portal-flags takes
+  pub const FLAGS: &[(&str, u32)] = &[
+    ("logout", 1),
+    ("user-switch", 2),
+    ("suspend", SUSPEND),
+    ("idle", IDLE),
+  ];
+
+  pub fn flag(name: &str) -> Option<u32> {
+    FLAGS.iter().find(|(n, _)| *n == name).map(|(_, bit)| *bit)
+  }
+
+  /// The flags argument of `Inhibit` that covers a category
+  pub fn flags_for(category: Option<Category>) -> u32 {
+    match category {
+      Some(Category::Sleep) | Some(Category::Lid) => SUSPEND,
+      Some(Category::Idle) => IDLE,
+      None | Some(Category::Both) => SUSPEND | IDLE,
+    }
+  }
+
+  /// The flags argument for the configuration, where --portal-flags
+  /// wins over --category
+  pub fn flags(config: &Config) -> u32 {
+    match &config.portal_flags {
+      Some(flags) => flags.split(':').filter_map(flag).fold(0, |a, b| a | b),
+      None => flags_for(config.category),
+    }
+  }
+
+  pub struct Portal {
     conn: Connection,
-    cookie: Option<u32>,
+    flags: u32,
+    handle: Option<OwnedObjectPath>,
   }
 
-  impl XfceScreenSaver {
-    pub fn new(conn: Connection) -> Self {
-      Self { conn, cookie: None }
+  impl Portal {
+    pub fn new(conn: Connection, flags: u32) -> Self {
+      Self {
+        conn,
+        flags,
+        handle: None,
+      }
+    }
+
+    async fn request(&self) -> Result<OwnedObjectPath> {
+      let portal = PortalInhibitProxy::new(&self.conn).await?;
+      let options = HashMap::from([("reason", Value::from(REASON))]);
+      // no parent window to speak of
+      Ok(portal.inhibit("", self.flags, options).await?)
+    }
+
+    async fn close(&self, handle: OwnedObjectPath) -> Result<()> {
+      let request = PortalRequestProxy::builder(&self.conn)
+        .path(handle)?
+        .build()
+        .await?;
+      Ok(request.close().await?)
     }
   }
 
   #[async_trait::async_trait]
-  impl Inhibitor for XfceScreenSaver {
-    async fn available(&self) -> Result<bool> {
-      let proxy = XfceScreenSaverProxy::new(&self.conn).await?;
-      Ok(proxy.0.introspect().await.is_ok())
+  impl Inhibitor for Portal {
+    async fn availability(&self) -> Availability {
+      let proxy = PortalInhibitProxy::new(&self.conn).await;
+      let proxy = proxy.map(PortalInhibitProxy::into_inner);
+      peer_availability(proxy, "xdg-desktop-portal").await
     }
 
     async fn inhibit(&mut self) -> Result<()> {
-      if self.cookie.is_some() {
+      if self.handle.is_some() {
         return Ok(());
       }
 
-      let manager = XfceScreenSaverProxy::new(&self.conn).await?;
-      let cookie = manager.inhibit("vigilare", "stay awake").await?;
-      self.cookie = Some(cookie);
+      self.handle = Some(self.request().await?);
       Ok(())
     }
 
     async fn uninhibit(&mut self) -> Result<()> {
-      if let Some(cookie) = self.cookie.take() {
-        let manager = XfceScreenSaverProxy::new(&self.conn).await?;
-        manager.uninhibit(cookie).await?;
+      if let Some(handle) = self.handle.take() {
+        self.close(handle).await?;
+      }
+      Ok(())
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      if self.handle.is_none() {
+        return Ok(());
+      }
+
+      let handle = self.request().await?;
+      let Some(old) = self.handle.replace(handle) else {
+        return Ok(());
+      };
+
+      // the old request may be what went stale
+      if let Err(e) = self.close(old).await {
+        debug!("Failed to close old portal request: {e}");
       }
       Ok(())
     }
   }
 }
 
+mod wayland_idle_inhibit {
+  use wayland_client::{
+    delegate_noop,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+      wl_compositor::WlCompositor, wl_registry, wl_surface::WlSurface,
+    },
+    Connection, Dispatch, EventQueue, QueueHandle,
+  };
+  use wayland_protocols::wp::idle_inhibit::zv1::client::{
+    zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1,
+    zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
+  };
+
+  use super::*;
+
+  const MANAGER: &str = "zwp_idle_inhibit_manager_v1";
+
+  pub struct WaylandIdleInhibit {
+    // connected on the first inhibit, and again after the compositor
+    // went away
+    session: Option<Session>,
+  }
+
+  impl WaylandIdleInhibit {
+    pub fn new() -> Self {
+      Self { session: None }
+    }
+  }
+
+  // The inhibitor hangs off a surface that is never mapped. Compositors
+  // that tie inhibitors to visible windows, like sway, treat one
+  // without a window as always visible.
+  struct Session {
+    queue: EventQueue<State>,
+    surface: WlSurface,
+    manager: ZwpIdleInhibitManagerV1,
+    inhibitor: Option<ZwpIdleInhibitorV1>,
+    // closing it takes everything above down with it
+    _conn: Connection,
+  }
+
+  impl Session {
+    fn connect() -> Result<Self> {
+      let conn = Connection::connect_to_env()
+        .context("failed to connect to the Wayland compositor")?;
+      let (globals, queue) = registry_queue_init::<State>(&conn)?;
+      let qh = queue.handle();
+
+      let compositor: WlCompositor = globals.bind(&qh, 1..=1, ())?;
+      let manager: ZwpIdleInhibitManagerV1 = globals
+        .bind(&qh, 1..=1, ())
+        .with_context(|| format!("compositor does not support {MANAGER}"))?;
+      let surface = compositor.create_surface(&qh, ());
+
+      Ok(Self {
+        queue,
+        surface,
+        manager,
+        inhibitor: None,
+        _conn: conn,
+      })
+    }
+
+    fn inhibit(&mut self) -> Result<()> {
+      if self.inhibitor.is_none() {
+        let qh = self.queue.handle();
+        let inhibitor = self.manager.create_inhibitor(&self.surface, &qh, ());
+        self.inhibitor = Some(inhibitor);
+      }
+      self.roundtrip()
+    }
+
+    // wait for the compositor to take everything sent so far, so that a
+    // lost connection shows up here
+    fn roundtrip(&mut self) -> Result<()> {
+      self
+        .queue
+        .roundtrip(&mut State)
+        .context("lost the Wayland compositor")?;
+      Ok(())
+    }
+  }
+
+  fn availability() -> Availability {
+    let globals = Connection::connect_to_env()
+      .map_err(anyhow::Error::from)
+      .and_then(|conn| Ok(registry_queue_init::<State>(&conn)?.0));
+    let globals = match globals {
+      Ok(globals) => globals,
+      Err(e) => return Availability::Unavailable(e.to_string()),
+    };
+
+    let advertised = globals
+      .contents()
+      .with_list(|list| list.iter().any(|g| g.interface == MANAGER));
+    if advertised {
+      Availability::Available
+    } else {
+      Availability::Unavailable(format!(
+        "compositor does not support {MANAGER}"
+      ))
+    }
+  }
+
+  // Talking to the compositor blocks, so it's done off the runtime,
+  // with the session moved over there and back
+  async fn blocking<T, F>(f: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+  {
+    tokio::task::spawn_blocking(f)
+      .await
+      .context("the Wayland call panicked")
+  }
+
+  #[async_trait::async_trait]
+  impl Inhibitor for WaylandIdleInhibit {
+    async fn availability(&self) -> Availability {
+      blocking(availability)
+        .await
+        .unwrap_or_else(|e| Availability::Unavailable(format!("{e:#}")))
+    }
+
+    async fn inhibit(&mut self) -> Result<()> {
+      let session = self.session.take();
+      let session = blocking(move || {
+        let mut session = match session {
+          Some(session) => session,
+          None => Session::connect()?,
+        };
+        session.inhibit()?;
+        anyhow::Ok(session)
+      })
+      .await??;
+      self.session = Some(session);
+      Ok(())
+    }
+
+    async fn uninhibit(&mut self) -> Result<()> {
+      let Some(mut session) = self.session.take() else {
+        return Ok(());
+      };
+
+      self.session = blocking(move || {
+        let Some(inhibitor) = session.inhibitor.take() else {
+          return Some(session);
+        };
+        inhibitor.destroy();
+        match session.roundtrip() {
+          Ok(()) => Some(session),
+          Err(e) => {
+            // the inhibitor went with the connection
+            debug!("{e:#}");
+            None
+          }
+        }
+      })
+      .await?;
+      Ok(())
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      let Some(mut session) = self.session.take() else {
+        return Ok(());
+      };
+
+      self.session = blocking(move || {
+        let alive = session.inhibitor.is_none() || session.roundtrip().is_ok();
+        alive.then_some(session)
+      })
+      .await?;
+      if self.session.is_some() {
+        return Ok(());
+      }
+
+      // the compositor restarted, set up again on the new one
+      self.inhibit().await
+    }
+  }
+
+  struct State;
+
+  impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+      _state: &mut Self,
+      _registry: &wl_registry::WlRegistry,
+      _event: wl_registry::Event,
+      _data: &GlobalListContents,
+      _conn: &Connection,
+      _qh: &QueueHandle<Self>,
+    ) {
+    }
+  }
+
+  delegate_noop!(State: WlCompositor);
+  delegate_noop!(State: ignore WlSurface);
+  delegate_noop!(State: ZwpIdleInhibitManagerV1);
+  delegate_noop!(State: ZwpIdleInhibitorV1);
+}
+
 mod mouse_jitter {
-  use std::time::Duration;
+  use std::sync::OnceLock;
 
+  use anyhow::anyhow;
   use enigo::{Coordinate, Enigo, Mouse as _};
 
   use super::*;
 
+  const WAYLAND_HINT: &str = "mouse jitter under Wayland needs write \
+    access to /dev/uinput; consider --mode logind instead";
+
+  // Opening the pointer makes a new display connection, which can be
+  // slow. The outcome won't change within a session, so probe once.
+  static AVAILABLE: OnceLock<Availability> = OnceLock::new();
+
   pub struct MouseJitter {
     interval: Duration,
-    task: Option<tokio::task::JoinHandle<()>>,
+    task: Option<JoinHandle<anyhow::Error>>,
   }
 
   impl MouseJitter {
@@ -348,11 +1649,76 @@ mod mouse_jitter {
     }
   }
 
+  // The pointer we jitter: XTEST where an X server is around, enigo
+  // everywhere else
+  enum Pointer {
+    Xtest(Box<xtest::XtestPointer>),
+    Enigo(Enigo),
+  }
+
+  impl Pointer {
+    fn location(&self) -> Result<(i32, i32)> {
+      match self {
+        Self::Xtest(pointer) => pointer.location(),
+        Self::Enigo(mouse) => Ok(mouse.location()?),
+      }
+    }
+
+    // move by a pixel and back to where it was
+    fn nudge(&mut self, pos: (i32, i32)) -> Result<()> {
+      match self {
+        Self::Xtest(pointer) => pointer.nudge(pos),
+        Self::Enigo(mouse) => {
+          mouse.move_mouse(0, 1, Coordinate::Rel)?;
+          mouse.move_mouse(pos.0, pos.1, Coordinate::Abs)?;
+          Ok(())
+        }
+      }
+    }
+  }
+
+  fn connect() -> Result<Pointer> {
+    if std::env::var_os("DISPLAY").is_some() {
+      match xtest::XtestPointer::connect() {
+        Ok(pointer) => return Ok(Pointer::Xtest(Box::new(pointer))),
+        Err(e) => debug!("XTEST unusable, falling back to enigo: {e}"),
+      }
+    }
+    connect_enigo().map(Pointer::Enigo)
+  }
+
+  // enigo only gives a generic error when it can't set up its virtual
+  // device, so tell Wayland users what's actually wrong
+  fn connect_enigo() -> Result<Enigo> {
+    let wayland = SessionType::detect() == SessionType::Wayland;
+    match Enigo::new(&Default::default()) {
+      Ok(mouse) if mouse.location().is_ok() => Ok(mouse),
+      Ok(_) if wayland => Err(anyhow!(WAYLAND_HINT)),
+      Ok(_) => Err(anyhow!("cannot read the cursor position")),
+      Err(e) if wayland => Err(anyhow!("{WAYLAND_HINT} ({e})")),
+      Err(e) => Err(anyhow!("cannot reach the display: {e}")),
+    }
+  }
+
+  // connecting blocks, so it's done off the runtime
+  async fn connect_blocking() -> Result<Pointer> {
+    tokio::task::spawn_blocking(connect)
+      .await
+      .context("connecting to the display panicked")?
+  }
+
   #[async_trait::async_trait]
   impl Inhibitor for MouseJitter {
-    async fn available(&self) -> Result<bool> {
-      let mouse = Enigo::new(&Default::default())?;
-      Ok(mouse.location().is_ok())
+    async fn availability(&self) -> Availability {
+      if let Some(availability) = AVAILABLE.get() {
+        return availability.clone();
+      }
+
+      let availability = match connect_blocking().await {
+        Ok(_) => Availability::Available,
+        Err(e) => Availability::Unavailable(e.to_string()),
+      };
+      AVAILABLE.get_or_init(|| availability).clone()
     }
 
     async fn inhibit(&mut self) -> Result<()> {
@@ -363,14 +1729,15 @@ mod mouse_jitter {
       let interval = self.interval;
       let history_len = (60.0 / interval.as_secs_f32()).ceil() as usize + 1;
       let mut history = Vec::with_capacity(history_len + 1);
-      let mut mouse = Enigo::new(&Default::default())?;
+      let mut mouse = connect_blocking().await?;
 
       let task = tokio::spawn(async move {
         loop {
           tokio::time::sleep(interval).await;
 
-          let Ok(pos) = mouse.location() else {
-            break;
+          let pos = match mouse.location() {
+            Ok(pos) => pos,
+            Err(e) => return e.context("failed to read the cursor position"),
           };
           history.push(pos);
 
@@ -385,12 +1752,9 @@ mod mouse_jitter {
           };
 
           // now let's jitter it just a little bit
-          mouse
-            .move_mouse(0, 1, Coordinate::Rel)
-            .expect("failed to move mouse");
-          mouse
-            .move_mouse(pos.0, pos.1, Coordinate::Abs)
-            .expect("failed to move mouse");
+          if let Err(e) = mouse.nudge(pos) {
+            return e.context("failed to move the cursor");
+          }
         }
       });
       self.task = Some(task);
@@ -404,5 +1768,291 @@ mod mouse_jitter {
       }
       Ok(())
     }
+
+    async fn stopped(&mut self) -> anyhow::Error {
+      task_stopped(&mut self.task).await
+    }
+  }
+
+  mod xtest {
+    use x11rb::{
+      connection::Connection as _,
+      protocol::{
+        xproto::{self, ConnectionExt as _},
+        xtest::ConnectionExt as _,
+      },
+      rust_connection::RustConnection,
+      CURRENT_TIME,
+    };
+
+    use super::*;
+
+    /// Moves the pointer with fake input from the XTEST extension
+    pub struct XtestPointer {
+      conn: RustConnection,
+      root: xproto::Window,
+    }
+
+    impl XtestPointer {
+      pub fn connect() -> Result<Self> {
+        let (conn, screen) = x11rb::connect(None)?;
+        conn.xtest_get_version(2, 2)?.reply()?;
+        let root = conn.setup().roots[screen].root;
+        Ok(Self { conn, root })
+      }
+
+      pub fn location(&self) -> Result<(i32, i32)> {
+        let reply = self.conn.query_pointer(self.root)?.reply()?;
+        Ok((reply.root_x.into(), reply.root_y.into()))
+      }
+
+      pub fn nudge(&mut self, pos: (i32, i32)) -> Result<()> {
+        let (x, y) = (i16::try_from(pos.0)?, i16::try_from(pos.1)?);
+        self.motion(x, y.saturating_add(1))?;
+        self.motion(x, y)?;
+        self.conn.flush()?;
+        Ok(())
+      }
+
+      fn motion(&self, x: i16, y: i16) -> Result<()> {
+        // a detail of 0 makes the coordinates absolute
+        self.conn.xtest_fake_input(
+          xproto::MOTION_NOTIFY_EVENT,
+          0,
+          CURRENT_TIME,
+          self.root,
+          x,
+          y,
+          0,
+        )?;
+        Ok(())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod mock {
+  use std::sync::{Arc, Mutex, MutexGuard};
+
+  use super::*;
+
+  /// What a `MockInhibitor` holds, shared with the test driving it
+  #[derive(Debug, Default)]
+  pub struct Mock {
+    pub held: bool,
+    // fail every call while set
+    pub failing: bool,
+    pub unavailable: bool,
+    pub calls: Vec<&'static str>,
+  }
+
+  /// An inhibitor holding nothing but its `Mock`
+  pub struct MockInhibitor(Arc<Mutex<Mock>>);
+
+  impl MockInhibitor {
+    pub fn new() -> (Self, Arc<Mutex<Mock>>) {
+      let mock = Arc::new(Mutex::new(Mock::default()));
+      (Self(mock.clone()), mock)
+    }
+
+    /// One that fails every call until its `failing` is cleared
+    pub fn failing() -> (Self, Arc<Mutex<Mock>>) {
+      let (inhibitor, mock) = Self::new();
+      mock.lock().unwrap().failing = true;
+      (inhibitor, mock)
+    }
+
+    fn call(&self, name: &'static str) -> Result<MutexGuard<'_, Mock>> {
+      let mut mock = self.0.lock().unwrap();
+      mock.calls.push(name);
+      if mock.failing {
+        anyhow::bail!("{name} failed");
+      }
+      Ok(mock)
+    }
+  }
+
+  /// Mocks that all inhibit, as the modes listed for `--mode` do
+  pub fn composite(
+    members: Vec<(InhibitMode, MockInhibitor)>,
+  ) -> Box<dyn Inhibitor> {
+    let members = members
+      .into_iter()
+      .map(|(mode, mock)| (mode, Box::new(mock) as Box<dyn Inhibitor>))
+      .collect();
+    Box::new(composite::Composite::new(members))
+  }
+
+  #[async_trait::async_trait]
+  impl Inhibitor for MockInhibitor {
+    async fn availability(&self) -> Availability {
+      if self.0.lock().unwrap().unavailable {
+        Availability::Unavailable("not running".into())
+      } else {
+        Availability::Available
+      }
+    }
+
+    async fn inhibit(&mut self) -> Result<()> {
+      self.call("inhibit")?.held = true;
+      Ok(())
+    }
+
+    async fn uninhibit(&mut self) -> Result<()> {
+      self.call("uninhibit")?.held = false;
+      Ok(())
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+      self.call("refresh").map(|_| ())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::inhibitor::mock::MockInhibitor;
+
+  #[test]
+  fn logind_blocks_sleep_and_idle_by_default() {
+    assert_eq!(logind::what(&Config::default()), "sleep:idle");
+
+    let config = Config {
+      category: Some(Category::Sleep),
+      ..Config::default()
+    };
+    assert_eq!(logind::what(&config), "sleep");
+  }
+
+  #[tokio::test]
+  async fn composite_goes_on_without_a_failing_member() {
+    let (good, held) = MockInhibitor::new();
+    let (bad, failing) = MockInhibitor::failing();
+    let mut composite = composite::Composite::new(vec![
+      (InhibitMode::Logind, Box::new(good)),
+      (InhibitMode::Xscreensaver, Box::new(bad)),
+    ]);
+
+    composite.inhibit().await.unwrap();
+    assert!(held.lock().unwrap().held);
+    assert_eq!(
+      composite.degraded().as_deref(),
+      Some("xscreensaver: inhibit failed")
+    );
+
+    // the failed member is tried again on renewal
+    failing.lock().unwrap().failing = false;
+    composite.refresh().await.unwrap();
+    assert_eq!(held.lock().unwrap().calls, ["inhibit", "refresh"]);
+    assert_eq!(failing.lock().unwrap().calls, ["inhibit", "inhibit"]);
+    assert!(failing.lock().unwrap().held);
+    assert_eq!(composite.degraded(), None);
+
+    composite.uninhibit().await.unwrap();
+    assert!(!held.lock().unwrap().held);
+    assert!(!failing.lock().unwrap().held);
+  }
+
+  #[tokio::test]
+  async fn composite_is_available_while_one_member_is() {
+    let (first, first_mock) = MockInhibitor::new();
+    let (second, second_mock) = MockInhibitor::new();
+    first_mock.lock().unwrap().unavailable = true;
+    let composite = composite::Composite::new(vec![
+      (InhibitMode::Logind, Box::new(first)),
+      (InhibitMode::Xscreensaver, Box::new(second)),
+    ]);
+    assert_eq!(composite.availability().await, Availability::Available);
+
+    second_mock.lock().unwrap().unavailable = true;
+    assert_eq!(
+      composite.availability().await,
+      Availability::Unavailable(
+        "logind: not running; xscreensaver: not running".into()
+      )
+    );
+  }
+
+  #[tokio::test]
+  async fn composite_fails_once_no_member_holds() {
+    let (first, _) = MockInhibitor::failing();
+    let (second, _) = MockInhibitor::failing();
+    let mut composite = composite::Composite::new(vec![
+      (InhibitMode::Logind, Box::new(first)),
+      (InhibitMode::Xscreensaver, Box::new(second)),
+    ]);
+
+    let e = composite.inhibit().await.unwrap_err();
+    assert_eq!(
+      e.to_string(),
+      "logind: inhibit failed; xscreensaver: inhibit failed"
+    );
+    // nothing is held, so nothing is left out either
+    assert_eq!(composite.degraded(), None);
+  }
+
+  #[tokio::test]
+  async fn composite_refresh_fails_once_every_renewal_has() {
+    let (first, first_mock) = MockInhibitor::new();
+    let (second, second_mock) = MockInhibitor::new();
+    let mut composite = composite::Composite::new(vec![
+      (InhibitMode::Logind, Box::new(first)),
+      (InhibitMode::Xscreensaver, Box::new(second)),
+    ]);
+    composite.inhibit().await.unwrap();
+
+    first_mock.lock().unwrap().failing = true;
+    composite.refresh().await.unwrap();
+    assert_eq!(
+      composite.degraded().as_deref(),
+      Some("logind: refresh failed")
+    );
+
+    second_mock.lock().unwrap().failing = true;
+    assert!(composite.refresh().await.is_err());
+  }
+
+  #[tokio::test]
+  async fn uninhibiting_a_composite_releases_every_member() {
+    let (first, first_mock) = MockInhibitor::new();
+    let (second, second_mock) = MockInhibitor::new();
+    let mut composite = composite::Composite::new(vec![
+      (InhibitMode::Logind, Box::new(first)),
+      (InhibitMode::Xscreensaver, Box::new(second)),
+    ]);
+    composite.inhibit().await.unwrap();
+
+    first_mock.lock().unwrap().failing = true;
+    let e = composite.uninhibit().await.unwrap_err();
+    assert_eq!(e.to_string(), "logind: uninhibit failed");
+    assert!(!second_mock.lock().unwrap().held);
+  }
+
+  #[tokio::test]
+  async fn fallback_sticks_with_the_first_that_inhibits() {
+    let (first, first_mock) = MockInhibitor::failing();
+    let (second, second_mock) = MockInhibitor::new();
+    let mut fallback = fallback::Fallback::new(vec![
+      (InhibitMode::Logind, Box::new(first)),
+      (InhibitMode::Xscreensaver, Box::new(second)),
+    ]);
+
+    fallback.inhibit().await.unwrap();
+    assert!(second_mock.lock().unwrap().held);
+    fallback.uninhibit().await.unwrap();
+
+    // the choice sticks even once the first would work again
+    first_mock.lock().unwrap().failing = false;
+    fallback.inhibit().await.unwrap();
+    assert_eq!(first_mock.lock().unwrap().calls, ["inhibit"]);
+    assert!(second_mock.lock().unwrap().held);
+
+    // and is only given up once it fails
+    fallback.uninhibit().await.unwrap();
+    second_mock.lock().unwrap().failing = true;
+    fallback.inhibit().await.unwrap();
+    assert!(first_mock.lock().unwrap().held);
   }
 }
@@ -0,0 +1,180 @@
+use std::{
+  env, fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+
+use crate::{inhibitor::InhibitMode, protocol::Instance};
+
+// where the system bus looks for the policies of services
+const DBUS_POLICY_DIR: &str = "/etc/dbus-1/system.d";
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Scope {
+  /// Install a systemd user unit
+  User,
+  /// Install a system-wide unit (needs root)
+  System,
+}
+
+impl Scope {
+  fn unit_dir(&self) -> Result<PathBuf> {
+    match self {
+      Self::System => Ok(PathBuf::from("/etc/systemd/system")),
+      Self::User => {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+          .map(PathBuf::from)
+          .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+          })
+          .context("neither $XDG_CONFIG_HOME nor $HOME is set")?;
+        Ok(config_home.join("systemd/user"))
+      }
+    }
+  }
+
+  fn target(&self) -> &'static str {
+    match self {
+      Self::User => "graphical-session.target",
+      Self::System => "multi-user.target",
+    }
+  }
+
+  fn systemctl(&self) -> &'static str {
+    match self {
+      Self::User => "systemctl --user",
+      Self::System => "sudo systemctl",
+    }
+  }
+}
+
+fn unit(
+  scope: Scope,
+  mode: InhibitMode,
+  instance: &Instance,
+) -> Result<String> {
+  let exe = env::current_exe().context("failed to locate vigilare binary")?;
+  let target = scope.target();
+  let bus_name = instance.service_name();
+  let mut args = format!("--mode {mode}");
+  // a system service has no session bus to serve on
+  if scope == Scope::System {
+    args.push_str(" --bus system");
+  }
+  if let Some(id) = instance.id() {
+    args.push_str(&format!(" --instance {id}"));
+  }
+
+  Ok(format!(
+    "[Unit]
+Description=Vigilare daemon
+PartOf={target}
+
+[Service]
+Type=dbus
+BusName={bus_name}
+ExecStart={exe} daemon {args}
+
+[Install]
+WantedBy={target}
+",
+    exe = exe.display(),
+  ))
+}
+
+// Without it the system bus doesn't let the daemon own its name, and
+// the unit would fail to start. The daemon runs as root, anyone may
+// call on it.
+fn dbus_policy(instance: &Instance) -> String {
+  let bus_name = instance.service_name();
+  format!(
+    r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <policy user="root">
+    <allow own="{bus_name}"/>
+  </policy>
+  <policy context="default">
+    <allow send_destination="{bus_name}"/>
+  </policy>
+</busconfig>
+"#
+  )
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+  let dir = path.parent().expect("installed files are in a directory");
+  fs::create_dir_all(dir)
+    .with_context(|| format!("failed to create {}", dir.display()))?;
+  fs::write(path, contents)
+    .with_context(|| format!("failed to write {}", path.display()))?;
+  println!("Installed {}", path.display());
+  Ok(())
+}
+
+/// Write a systemd unit for the daemon, and for a system one the D-Bus
+/// policy it needs, and tell the user how to enable it
+pub fn install(
+  scope: Scope,
+  mode: InhibitMode,
+  instance: &Instance,
+) -> Result<()> {
+  let unit_name = instance.file_name("service");
+  let path = scope.unit_dir()?.join(&unit_name);
+  write(&path, &unit(scope, mode, instance)?)?;
+
+  let systemctl = scope.systemctl();
+  println!("Enable it with:");
+  if scope == Scope::System {
+    let policy = Path::new(DBUS_POLICY_DIR)
+      .join(format!("{}.conf", instance.service_name()));
+    write(&policy, &dbus_policy(instance))?;
+    // the bus only reads the policies again when told to
+    println!("  {systemctl} reload dbus");
+  }
+  println!("  {systemctl} daemon-reload");
+  println!("  {systemctl} enable --now {unit_name}");
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn instances_reach_the_unit() {
+    let instance = Instance::parse("work").unwrap();
+    let unit = unit(Scope::User, InhibitMode::Logind, &instance).unwrap();
+    assert!(
+      unit.contains("\nBusName=org.shou.Vigilare.work\n"),
+      "{unit}"
+    );
+    assert!(
+      unit.contains(" daemon --mode logind --instance work\n"),
+      "{unit}"
+    );
+  }
+
+  #[test]
+  fn system_units_serve_on_the_system_bus() {
+    let unit =
+      unit(Scope::System, InhibitMode::Auto, &Instance::default()).unwrap();
+    assert!(unit.contains("\nBusName=org.shou.Vigilare\n"), "{unit}");
+    assert!(
+      unit.contains(" daemon --mode auto --bus system\n"),
+      "{unit}"
+    );
+  }
+
+  #[test]
+  fn the_policy_lets_the_daemon_own_its_name() {
+    let policy = dbus_policy(&Instance::parse("work").unwrap());
+    assert!(policy.contains(r#"<allow own="org.shou.Vigilare.work"/>"#));
+    assert!(
+      policy.contains(r#"<allow send_destination="org.shou.Vigilare.work"/>"#)
+    );
+  }
+}
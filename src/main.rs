@@ -1,70 +1,529 @@
-use clap::{Parser, Subcommand};
+use std::{
+  os::unix::process::ExitStatusExt as _, path::PathBuf, time::Duration,
+};
+
+use clap::{ArgAction, Parser, Subcommand};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
 mod client;
+mod config;
+mod config_file;
 mod daemon;
+mod detach;
+mod guard;
 mod helper;
+mod idle;
 mod inhibitor;
+mod install;
+mod metrics;
+mod notify;
 mod protocol;
+mod session;
 mod signals;
+mod socket;
+mod state;
+mod template;
 
+use config::{Config, ConfigArgs};
 use inhibitor::InhibitMode;
-use protocol::DurationUpdate;
+use protocol::{DurationUpdate, UpdateOptions};
 
 pub use daemon::Daemon;
 
 #[derive(Parser)]
 struct Cli {
+  /// Log more details, repeat for even more
+  #[clap(short, long, global = true, action = ArgAction::Count)]
+  verbose: u8,
+
+  /// Only log warnings and errors
+  #[clap(short, long, global = true, conflicts_with = "verbose")]
+  quiet: bool,
+
   #[clap(subcommand)]
   cmd: Commands,
 }
 
+impl Cli {
+  // RUST_LOG still takes precedence when set
+  fn init_tracing(&self) {
+    let level = match (self.quiet, self.verbose) {
+      (true, _) => LevelFilter::WARN,
+      (false, 0) => LevelFilter::INFO,
+      (false, 1) => LevelFilter::DEBUG,
+      (false, _) => LevelFilter::TRACE,
+    };
+
+    let filter = EnvFilter::builder()
+      .with_default_directive(level.into())
+      .from_env_lossy();
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+  }
+}
+
 #[derive(Subcommand)]
 enum Commands {
   /// Start the daemon
   Daemon {
-    /// Inhibit mechanism
-    #[clap(short, long, default_value = "mouse-jitter", value_enum)]
-    mode: InhibitMode,
+    #[clap(flatten)]
+    config: ConfigArgs,
+
+    /// Inhibit for the given duration, then exit
+    #[clap(long, value_parser = helper::parse_duration)]
+    once: Option<Duration>,
+
+    /// Start inhibiting for the given duration right away, then keep
+    /// running as usual
+    #[clap(
+      long,
+      value_parser = helper::parse_duration,
+      conflicts_with = "once"
+    )]
+    inhibit_immediately: Option<Duration>,
+
+    /// Start inhibiting right away with no deadline, until a message
+    /// clears the vigil
+    #[clap(long, conflicts_with_all = ["once", "inhibit_immediately"])]
+    no_timer: bool,
+
+    /// Run in the background, returning once the daemon is on the bus
+    #[clap(long)]
+    detach: bool,
+
+    /// Where to write the PID of the detached daemon
+    /// [default: $XDG_RUNTIME_DIR/vigilare.pid]
+    #[clap(long, requires = "detach")]
+    pid_file: Option<PathBuf>,
+
+    /// Print the bus name the daemon would claim, then exit
+    #[clap(long, conflicts_with = "detach")]
+    print_dbus_name: bool,
+
+    /// Print the object path the daemon would serve at, then exit
+    #[clap(long, conflicts_with = "detach")]
+    print_object_path: bool,
+
+    /// Try each mode with a brief inhibit and release, report how it
+    /// went and exit, failing if any of them doesn't work
+    #[clap(long, conflicts_with = "detach")]
+    check: bool,
   },
 
   /// Subscribe to status updates
-  Monitor,
+  Monitor {
+    #[clap(flatten)]
+    format: client::FormatArgs,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Print the status once, like the first line of `monitor`, failing
+  /// if the daemon isn't running
+  Status {
+    #[clap(flatten)]
+    format: client::FormatArgs,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
 
   /// Control the daemon
   Msg {
     /// Update the vigil duration. Prefix with "+" to add, "-" to
-    /// subtract.  Duration syntax: "1h", "30m", "1d", etc.
-    #[clap(value_parser = helper::parse_duration_update, allow_hyphen_values = true)]
+    /// subtract.  Duration syntax: "1h", "30m", "1.5h", "1d", etc.
+    /// Pass "-" to read the update from stdin
+    #[clap(value_parser = helper::parse_duration_update_arg, allow_hyphen_values = true)]
     update: DurationUpdate,
+
+    /// Restart the countdown whenever the user is active, so that the
+    /// vigil only ends once they've been away for its whole length.
+    /// Needs the daemon to run with --idle-timeout
+    #[clap(long)]
+    rolling: bool,
+
+    /// Tag the vigil with who asked for it, shown in the status
+    #[clap(long)]
+    label: Option<String>,
+
+    #[clap(flatten)]
+    output: client::OutputArgs,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Take time off the vigil, ending it if that leaves none
+  Snooze {
+    /// How much to take off, e.g. "5m"
+    #[clap(value_parser = helper::parse_duration)]
+    duration: Duration,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// End the vigil, same as `msg 0`
+  #[clap(visible_alias = "clear")]
+  Stop {
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Keep awake until the given local time, after which the machine
+  /// may sleep again. A time that has passed today means tomorrow
+  SleepAt {
+    /// The time of day, e.g. "23:30" or "00:00"
+    #[clap(value_parser = helper::parse_clock_time)]
+    time: helper::ClockTime,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Stretch the time left in the vigil
+  Extend {
+    /// Multiply the time left by this, e.g. 1.5 for half as long again
+    #[clap(long, value_parser = helper::parse_factor)]
+    factor: f64,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
   },
 
+  /// Run a command, keeping the machine awake until it exits, and exit
+  /// with its status
+  Run {
+    /// The command and its arguments
+    #[clap(required = true, trailing_var_arg = true)]
+    command: Vec<String>,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Switch the running daemon to another inhibit mode, which it
+  /// keeps across restarts
+  SetMode {
+    #[clap(value_enum)]
+    mode: InhibitMode,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// List the running timers, i.e. the vigil, named by its --label,
+  /// with the time each has left
+  List {
+    /// Print them as a JSON array
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// End the vigil if it was set with the given --label, leaving
+  /// anyone else's alone
+  Cancel {
+    name: String,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// Step through preset durations: off, then each preset in turn,
+  /// then off again. Handy as a status bar click action
+  Cycle {
+    /// The durations to step through [default: the daemon's
+    /// --presets]
+    #[clap(long, value_delimiter = ',', value_parser = helper::parse_duration)]
+    presets: Vec<Duration>,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// End the vigil if one is set, otherwise set one. Handy as a key
+  /// binding
+  Toggle {
+    /// How long the vigil set lasts
+    #[clap(default_value = "1h", value_parser = helper::parse_duration)]
+    duration: Duration,
+
+    #[clap(flatten)]
+    connect: client::ConnectArgs,
+  },
+
+  /// List the inhibitor locks held through logind by any program.
+  /// Inhibitions made in other ways, e.g. with xfce4-power-manager,
+  /// don't show up here
+  Inhibitors,
+
   /// List all modes available on the system
-  ListModes,
-}
+  ListModes {
+    /// Also list modes that are not available
+    #[clap(long)]
+    all: bool,
+
+    /// Print as a JSON array
+    #[clap(long)]
+    json: bool,
+
+    // for what each mode's inhibitors identify as, which --verbose
+    // shows
+    #[clap(flatten)]
+    config: ConfigArgs,
+  },
+
+  /// Explain each mode and whether it's available on the system
+  Describe,
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-  tracing_subscriber::fmt::init();
+  /// Install a systemd unit that starts the daemon
+  Install {
+    #[clap(long, default_value = "user", value_enum)]
+    scope: install::Scope,
+
+    /// Inhibit mechanism the unit starts the daemon with, by default
+    /// the first that is available whenever the daemon starts
+    #[clap(short, long, default_value = "auto", value_enum)]
+    mode: InhibitMode,
+
+    /// The instance of the daemon the unit starts, see `daemon
+    /// --instance`
+    #[clap(long, value_parser = protocol::Instance::parse)]
+    instance: Option<protocol::Instance>,
+  },
+
+  /// Print the configuration the daemon would use, as JSON
+  Config {
+    #[clap(flatten)]
+    config: ConfigArgs,
+  },
+}
 
+fn main() -> anyhow::Result<()> {
   let cli = Cli::parse();
 
+  // read before detaching, since that leaves the working directory
+  // a relative --config-file is found in
+  let daemon_config = match &cli.cmd {
+    Commands::Daemon { config, .. } => Some(config.resolve()?),
+    _ => None,
+  };
+
+  // forking is only sound before the runtime starts its threads
+  let detached = match (&cli.cmd, &daemon_config) {
+    (
+      Commands::Daemon {
+        detach: true,
+        pid_file,
+        ..
+      },
+      Some(config),
+    ) => {
+      let pid_file = pid_file
+        .clone()
+        .unwrap_or_else(|| detach::default_pid_file(&config.instance));
+      Some(detach::detach(&pid_file)?)
+    }
+    _ => None,
+  };
+
+  cli.init_tracing();
+
+  tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()?
+    .block_on(run(cli, daemon_config, detached))
+}
+
+async fn run(
+  cli: Cli,
+  daemon_config: Option<Config>,
+  detached: Option<detach::Detached>,
+) -> anyhow::Result<()> {
+  let verbose = cli.verbose > 0;
   match cli.cmd {
-    Commands::Daemon { mode } => {
-      let mut daemon = daemon::Daemon::new(mode).await?;
-      daemon.run().await.expect("Failed to run daemon");
+    Commands::Daemon {
+      once,
+      inhibit_immediately,
+      no_timer,
+      print_dbus_name,
+      print_object_path,
+      check,
+      ..
+    } => {
+      let config = daemon_config.expect("resolved in main");
+      if print_dbus_name || print_object_path {
+        if print_dbus_name {
+          println!("{}", config.instance.service_name());
+        }
+        if print_object_path {
+          println!("{}", config.instance.object_path());
+        }
+        return Ok(());
+      }
+      if check {
+        return check_modes(&config).await;
+      }
+
+      let connections = protocol::Connections::retrying();
+      let mut daemon = daemon::Daemon::new(&config, connections).await?;
+      if let Some(detached) = detached {
+        daemon.detached(detached);
+      }
+      if let Some(duration) = once {
+        daemon.once(duration)?;
+      }
+      if let Some(duration) = inhibit_immediately {
+        daemon.start_vigil(duration)?;
+      }
+      if no_timer {
+        daemon.start_indefinite();
+      }
+      daemon.run(signals::exit_signal()).await?;
     }
-    Commands::Msg { update } => {
-      client::msg(update).await.expect("Failed to update");
+    Commands::Msg {
+      update,
+      rolling,
+      label,
+      output,
+      connect,
+    } => {
+      let options = UpdateOptions {
+        rolling,
+        label: label.unwrap_or_default(),
+      };
+      client::msg(update, options, &output, &connect)
+        .await
+        .expect("Failed to update");
     }
-    Commands::Monitor => {
-      client::monitor_forever().await.expect("Failed to monitor");
+    Commands::Snooze { duration, connect } => {
+      let update = DurationUpdate::Sub(duration);
+      client::msg(update, Default::default(), &Default::default(), &connect)
+        .await
+        .expect("Failed to snooze");
     }
-    Commands::ListModes => {
-      for mode in inhibitor::available_modes().await {
-        println!("{}", serde_variant::to_variant_name(&mode).unwrap());
+    Commands::Stop { connect } => {
+      client::stop(&connect).await.expect("Failed to stop");
+    }
+    Commands::SleepAt { time, connect } => {
+      let update = DurationUpdate::Set(time.until_next());
+      client::msg(update, Default::default(), &Default::default(), &connect)
+        .await
+        .expect("Failed to set the vigil");
+    }
+    Commands::Extend { factor, connect } => {
+      client::extend(factor, &connect)
+        .await
+        .expect("Failed to extend");
+    }
+    Commands::Run { command, connect } => {
+      let status = client::run(&command, &connect).await?;
+      // like a shell, report death by a signal as 128 + the signal
+      let code = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1);
+      std::process::exit(code);
+    }
+    Commands::SetMode { mode, connect } => {
+      client::set_mode(mode, &connect)
+        .await
+        .expect("Failed to switch mode");
+    }
+    Commands::List { json, connect } => {
+      client::list(json, &connect).await.expect("Failed to list");
+    }
+    Commands::Cancel { name, connect } => {
+      client::cancel(&name, &connect)
+        .await
+        .expect("Failed to cancel");
+    }
+    Commands::Cycle { presets, connect } => {
+      client::cycle(&presets, &connect)
+        .await
+        .expect("Failed to cycle");
+    }
+    Commands::Toggle { duration, connect } => {
+      client::toggle(duration, &connect).await?;
+    }
+    Commands::Monitor { format, connect } => {
+      client::monitor_forever(&format, &connect)
+        .await
+        .expect("Failed to monitor");
+    }
+    Commands::Status { format, connect } => {
+      client::status(&format, &connect).await?;
+    }
+    Commands::ListModes { all, json, config } => {
+      let mut modes = inhibitor::probe_modes(&config.resolve()?).await;
+      if !all {
+        modes.retain(|m| m.available);
+      }
+
+      if json {
+        println!("{}", serde_json::to_string(&modes)?);
+      } else {
+        for m in modes {
+          let mut line = m.mode.to_string();
+          if let (true, Some(footprint)) = (verbose, &m.footprint) {
+            line.push_str(&format!(" ({footprint})"));
+          }
+          if let Some(reason) = &m.reason {
+            line.push_str(&format!(" (unavailable: {reason})"));
+          }
+          println!("{line}");
+        }
       }
     }
+    Commands::Inhibitors => {
+      for lock in inhibitor::list_inhibitors().await? {
+        println!("{} (uid {}, pid {})", lock.who, lock.uid, lock.pid);
+        println!("  what: {}, mode: {}", lock.what, lock.mode);
+        println!("  why: {}", lock.why);
+      }
+    }
+    Commands::Describe => {
+      for m in inhibitor::probe_modes(&Config::default()).await {
+        match m.reason {
+          None => println!("{} (available)", m.mode),
+          Some(reason) => println!("{} (unavailable: {reason})", m.mode),
+        }
+        println!("  {}", m.mode.description());
+      }
+    }
+    Commands::Install {
+      scope,
+      mode,
+      instance,
+    } => {
+      install::install(scope, mode, &instance.unwrap_or_default())?;
+    }
+    Commands::Config { config } => {
+      println!("{}", config.resolve()?.json());
+    }
   }
 
   Ok(())
 }
+
+async fn check_modes(config: &Config) -> anyhow::Result<()> {
+  let connections = protocol::Connections::default();
+  let mut failed = Vec::new();
+  for mode in inhibitor::resolve_auto(&config.modes, config).await? {
+    match inhibitor::check_mode(mode, config, &connections).await {
+      Ok(()) => println!("{mode}: ok"),
+      Err(e) => {
+        println!("{mode}: {e:#}");
+        failed.push(mode.to_string());
+      }
+    }
+  }
+
+  match failed.as_slice() {
+    [] => Ok(()),
+    failed => anyhow::bail!("not working: {}", failed.join(", ")),
+  }
+}
@@ -0,0 +1,148 @@
+//! Prometheus metrics over plain HTTP, for `--metrics-addr`.
+//!
+//! Any `GET /metrics` gets the current figures in the text exposition
+//! format; the connection is closed after each response.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context as _, Result};
+use tokio::{
+  io::{AsyncReadExt as _, AsyncWriteExt as _},
+  net::{TcpListener, TcpStream},
+  sync::{mpsc, oneshot},
+};
+use tracing::{debug, warn};
+
+use crate::daemon::DaemonMessage;
+
+// a scraper's request fits easily, anything longer is not one
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The figures exported, as of when they were asked for
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+  pub active: bool,
+  // None when the vigil has no deadline
+  pub remaining_seconds: Option<u64>,
+  // how long the inhibitor has been held since the daemon started
+  pub inhibited: Duration,
+  pub inhibit_failures: u64,
+}
+
+impl Metrics {
+  fn render(&self) -> String {
+    let remaining = match self.remaining_seconds {
+      _ if !self.active => "0".to_string(),
+      None => "+Inf".to_string(),
+      Some(secs) => secs.to_string(),
+    };
+    let metrics = [
+      (
+        "vigilare_active",
+        "gauge",
+        "Whether a vigil is set",
+        u8::from(self.active).to_string(),
+      ),
+      (
+        "vigilare_remaining_seconds",
+        "gauge",
+        "Seconds until the vigil ends, +Inf when it has no deadline",
+        remaining,
+      ),
+      (
+        "vigilare_total_inhibited_seconds",
+        "counter",
+        "Seconds the inhibitor has been held since the daemon started",
+        format!("{:.3}", self.inhibited.as_secs_f64()),
+      ),
+      (
+        "vigilare_inhibit_failures_total",
+        "counter",
+        "Attempts to inhibit or re-assert the inhibitor that failed",
+        self.inhibit_failures.to_string(),
+      ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+      out.push_str(&format!("# HELP {name} {help}\n"));
+      out.push_str(&format!("# TYPE {name} {kind}\n"));
+      out.push_str(&format!("{name} {value}\n"));
+    }
+    out
+  }
+}
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+  TcpListener::bind(addr)
+    .await
+    .with_context(|| format!("failed to serve metrics on {addr}"))
+}
+
+// weak, so that the daemon still notices its control service going
+// away
+pub async fn serve(
+  listener: TcpListener,
+  sender: mpsc::WeakSender<DaemonMessage>,
+) {
+  loop {
+    let stream = match listener.accept().await {
+      Ok((stream, _)) => stream,
+      Err(e) => {
+        warn!("Failed to accept metrics connection: {e}");
+        continue;
+      }
+    };
+
+    let sender = sender.clone();
+    tokio::spawn(async move {
+      let handled =
+        tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, sender));
+      match handled.await {
+        Ok(Err(e)) => debug!("Metrics request failed: {e:#}"),
+        Err(_) => debug!("Metrics request timed out"),
+        Ok(Ok(())) => {}
+      }
+    });
+  }
+}
+
+async fn handle(
+  mut stream: TcpStream,
+  sender: mpsc::WeakSender<DaemonMessage>,
+) -> Result<()> {
+  let mut request = Vec::new();
+  let mut buf = [0; 1024];
+  while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+    let n = stream.read(&mut buf).await?;
+    if n == 0 || request.len() + n > MAX_REQUEST {
+      return Ok(());
+    }
+    request.extend_from_slice(&buf[..n]);
+  }
+
+  let request = String::from_utf8_lossy(&request);
+  let mut words = request.split_whitespace();
+  let (status, body) = match (words.next(), words.next()) {
+    (Some("GET"), Some("/metrics")) => {
+      let sender = sender.upgrade().context("the daemon is shutting down")?;
+      let (reply, receiver) = oneshot::channel();
+      sender.send(DaemonMessage::MetricsRequest(reply)).await?;
+      ("200 OK", receiver.await?.render())
+    }
+    (Some("GET"), _) => ("404 Not Found", "try /metrics\n".to_string()),
+    _ => ("405 Method Not Allowed", String::new()),
+  };
+
+  let response = format!(
+    "HTTP/1.1 {status}\r\n\
+     Content-Type: text/plain; version=0.0.4\r\n\
+     Content-Length: {}\r\n\
+     Connection: close\r\n\r\n{body}",
+    body.len()
+  );
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
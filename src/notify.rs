@@ -0,0 +1,78 @@
+//! Desktop notifications over org.freedesktop.Notifications
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use zbus::{zvariant::Value, Connection, Proxy};
+
+const SERVICE: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+
+/// How insistently the server should show a notification
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+  Low,
+  #[default]
+  Normal,
+  /// Usually stays up until dismissed
+  Critical,
+}
+
+impl Urgency {
+  // the byte the specification assigns to each
+  fn byte(self) -> u8 {
+    match self {
+      Self::Low => 0,
+      Self::Normal => 1,
+      Self::Critical => 2,
+    }
+  }
+}
+
+pub struct Notification<'a> {
+  pub app_name: &'a str,
+  pub summary: &'a str,
+  pub body: &'a str,
+  pub urgency: Urgency,
+  // the id of an earlier notification to update in place, 0 for none
+  pub replaces_id: u32,
+  // stays up until closed rather than timing out
+  pub persistent: bool,
+}
+
+/// Show a notification, returning the id the server gave it
+pub async fn send(
+  conn: &Connection,
+  notification: &Notification<'_>,
+) -> Result<u32> {
+  // called by hand, `Notify` takes more arguments than a generated
+  // proxy method would pass clippy with
+  let proxy = Proxy::new(conn, SERVICE, PATH, SERVICE).await?;
+  let actions: &[&str] = &[];
+  let mut hints: HashMap<&str, Value> = HashMap::new();
+  hints.insert("urgency", notification.urgency.byte().into());
+  // 0 never times out, -1 leaves the timeout to the server
+  let timeout = if notification.persistent { 0 } else { -1i32 };
+  let args = (
+    notification.app_name,
+    notification.replaces_id,
+    "",
+    notification.summary,
+    notification.body,
+    actions,
+    hints,
+    timeout,
+  );
+  let id = proxy.call("Notify", &args).await?;
+  Ok(id)
+}
+
+/// Take down a notification shown earlier
+pub async fn close(conn: &Connection, id: u32) -> Result<()> {
+  let proxy = Proxy::new(conn, SERVICE, PATH, SERVICE).await?;
+  proxy.call::<_, _, ()>("CloseNotification", &(id,)).await?;
+  Ok(())
+}
@@ -1,15 +1,235 @@
-use std::time::Duration;
+use std::{
+  fmt,
+  time::{Duration, SystemTime},
+};
 
+use anyhow::Context as _;
+use clap::ValueEnum;
+use duration_string::DurationString;
 use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::warn;
 use zbus::zvariant::{self};
 
-#[derive(Debug, Clone, Serialize, Deserialize, zvariant::Type)]
+/// The message bus the daemon serves its interface on
+#[derive(
+  Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bus {
+  #[default]
+  Session,
+  System,
+}
+
+impl Bus {
+  pub async fn connect(self) -> zbus::Result<zbus::Connection> {
+    match self {
+      Self::Session => zbus::Connection::session().await,
+      Self::System => zbus::Connection::system().await,
+    }
+  }
+}
+
+/// Connections to the session and system bus, opened on first use and
+/// shared by the service and the inhibitors
+#[derive(Default)]
+pub struct Connections {
+  // keep trying for a while when a bus isn't up yet
+  retry: bool,
+  session: OnceCell<zbus::Connection>,
+  system: OnceCell<zbus::Connection>,
+}
+
+// the longest wait between attempts to reach a bus, about half a
+// minute in total
+const MAX_BUS_BACKOFF: Duration = Duration::from_secs(16);
+
+impl Connections {
+  /// Connections that wait for a bus that isn't up yet, as may happen
+  /// early in a session
+  pub fn retrying() -> Self {
+    Self {
+      retry: true,
+      ..Self::default()
+    }
+  }
+
+  /// Connections that use `conn` for `bus` rather than opening one,
+  /// such as one end of a private connection in tests
+  pub fn with(bus: Bus, conn: zbus::Connection) -> Self {
+    let preset = OnceCell::new_with(Some(conn));
+    match bus {
+      Bus::Session => Self {
+        session: preset,
+        ..Self::default()
+      },
+      Bus::System => Self {
+        system: preset,
+        ..Self::default()
+      },
+    }
+  }
+
+  pub async fn get(&self, bus: Bus) -> anyhow::Result<zbus::Connection> {
+    self.get_with(bus, self.retry).await
+  }
+
+  /// Like `get`, but gives up right away, for things that can do
+  /// without the bus
+  pub async fn try_get(&self, bus: Bus) -> anyhow::Result<zbus::Connection> {
+    self.get_with(bus, false).await
+  }
+
+  async fn get_with(
+    &self,
+    bus: Bus,
+    retry: bool,
+  ) -> anyhow::Result<zbus::Connection> {
+    let cell = match bus {
+      Bus::Session => &self.session,
+      Bus::System => &self.system,
+    };
+    let conn = cell.get_or_try_init(|| connect(bus, retry)).await?;
+    Ok(conn.clone())
+  }
+}
+
+async fn connect(bus: Bus, retry: bool) -> anyhow::Result<zbus::Connection> {
+  let mut backoff = Duration::from_millis(250);
+  loop {
+    let e = match bus.connect().await {
+      Ok(conn) => return Ok(conn),
+      Err(e) => e,
+    };
+
+    // a bus that's there but refuses us won't change its mind
+    let not_ready =
+      matches!(e, zbus::Error::InputOutput(_) | zbus::Error::Address(_));
+    if !retry || !not_ready || backoff > MAX_BUS_BACKOFF {
+      return Err(anyhow::Error::new(e))
+        .with_context(|| format!("{bus} bus not reachable"));
+    }
+
+    warn!(
+      "The {bus} bus is not reachable ({e}), retrying in {}",
+      DurationString::from(backoff)
+    );
+    tokio::time::sleep(backoff).await;
+    backoff *= 2;
+  }
+}
+
+impl fmt::Display for Bus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Session => f.write_str("session"),
+      Self::System => f.write_str("system"),
+    }
+  }
+}
+
+/// How clients talk to the daemon
+#[derive(
+  Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+  /// The D-Bus interface on the chosen bus
+  #[default]
+  Dbus,
+  /// JSON lines over a Unix socket in $XDG_RUNTIME_DIR
+  Socket,
+}
+
+/// Well-known bus name claimed by the daemon
+pub const SERVICE_NAME: &str = "org.shou.Vigilare";
+/// Path the daemon serves its interface at
+pub const OBJECT_PATH: &str = "/org/shou/Vigilare";
+
+/// Which of several daemons sharing a bus, e.g. "work" for one at
+/// org.shou.Vigilare.work and /org/shou/Vigilare/work. The default
+/// instance has no suffix.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Instance(Option<String>);
+
+impl Instance {
+  /// Accepts what is valid in both a bus name and an object path
+  pub fn parse(s: &str) -> Result<Self, String> {
+    let valid = s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+      && s.bytes().next().is_some_and(|b| !b.is_ascii_digit());
+    if !valid {
+      return Err(format!(
+        "invalid instance '{s}': use letters, digits and '_', not \
+         starting with a digit"
+      ));
+    }
+    Ok(Self(Some(s.to_string())))
+  }
+
+  /// The name given with --instance, None for the default instance
+  pub fn id(&self) -> Option<&str> {
+    self.0.as_deref()
+  }
+
+  pub fn service_name(&self) -> String {
+    match &self.0 {
+      Some(id) => format!("{SERVICE_NAME}.{id}"),
+      None => SERVICE_NAME.to_string(),
+    }
+  }
+
+  pub fn object_path(&self) -> String {
+    match &self.0 {
+      Some(id) => format!("{OBJECT_PATH}/{id}"),
+      None => OBJECT_PATH.to_string(),
+    }
+  }
+
+  /// The name of a file of this instance's, e.g. "vigilare-work.sock"
+  pub fn file_name(&self, extension: &str) -> String {
+    match &self.0 {
+      Some(id) => format!("vigilare-{id}.{extension}"),
+      None => format!("vigilare.{extension}"),
+    }
+  }
+}
+
+/// A change to the vigil's deadline.
+///
+/// On D-Bus this is `(u(tu))`: the variant index, then the duration as
+/// seconds and nanoseconds. New variants go at the end, so that peers
+/// built before them still decode the existing ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, zvariant::Type)]
 pub enum DurationUpdate {
   Add(Duration),
   Sub(Duration),
   Set(Duration),
 }
 
+/// Extras that can come with a duration update
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize, zvariant::Type,
+)]
+pub struct UpdateOptions {
+  // restart the countdown on user activity
+  pub rolling: bool,
+  // who asked for the vigil, empty for nobody in particular
+  pub label: String,
+}
+
+// the syntax `helper::parse_duration_update` accepts
+impl fmt::Display for DurationUpdate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Add(d) => write!(f, "+{}", DurationString::from(*d)),
+      Self::Sub(d) => write!(f, "-{}", DurationString::from(*d)),
+      Self::Set(d) if d.is_zero() => f.write_str("0"),
+      Self::Set(d) => write!(f, "{}", DurationString::from(*d)),
+    }
+  }
+}
+
 #[derive(
   Debug,
   Clone,
@@ -21,18 +241,130 @@ pub enum DurationUpdate {
 )]
 pub struct Status {
   pub active: bool,
-  // UNIX epoch time
+  // UNIX epoch time, 0 when the vigil has no deadline
   pub wake_until: u64,
+  // UNIX epoch time the vigil began, 0 when inactive
+  pub started_at: u64,
+  // label of the last update that carried one, empty when none did
+  pub label: String,
+  // UNIX epoch time the last vigil ran out, 0 when it was cleared or
+  // another one has started since
+  pub last_expired_at: u64,
+  // the modes that failed to inhibit while the others hold, with why,
+  // empty when all of them hold
+  pub degraded: String,
 }
 
-#[zbus::proxy(
-  interface = "org.shou.Vigilare",
-  default_service = "org.shou.Vigilare",
-  default_path = "/org/shou/Vigilare"
-)]
+impl Status {
+  /// Whether the vigil runs until it is cleared rather than timing out
+  pub fn indefinite(&self) -> bool {
+    self.active && self.wake_until == 0
+  }
+
+  /// The running vigil as a timer named by its label, with the
+  /// seconds it has left, u64::MAX when it has no deadline
+  pub fn timers(&self) -> Vec<(String, u64)> {
+    if !self.active {
+      return Vec::new();
+    }
+    let left = if self.indefinite() {
+      u64::MAX
+    } else {
+      let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+      // partial seconds count as whole ones
+      let left = Duration::from_secs(self.wake_until).saturating_sub(now);
+      left.as_secs() + u64::from(left.subsec_nanos() > 0)
+    };
+    vec![(self.label.clone(), left)]
+  }
+}
+
+// the interface name has to be a literal here, keep it in sync with
+// `DbusService` in daemon.rs
+#[zbus::proxy(interface = "org.shou.Vigilare")]
 trait DbusVigilare {
   async fn update(&self, update: DurationUpdate) -> zbus::Result<()>;
 
+  async fn update_with(
+    &self,
+    update: DurationUpdate,
+    options: UpdateOptions,
+  ) -> zbus::Result<()>;
+
+  async fn extend_by_factor(&self, factor: f64) -> zbus::Result<()>;
+
+  async fn set_mode(&self, mode: &str) -> zbus::Result<()>;
+
+  async fn cancel(&self, name: &str) -> zbus::Result<()>;
+
+  async fn timers(&self) -> zbus::Result<Vec<(String, u64)>>;
+
+  async fn presets(&self) -> zbus::Result<Vec<String>>;
+
+  #[zbus(signal)]
+  fn mode_changed(&self, mode: &str) -> zbus::Result<()>;
+
   #[zbus(property)]
   fn status(&self) -> zbus::Result<Status>;
 }
+
+#[cfg(test)]
+mod tests {
+  use zvariant::{
+    serialized::Context, to_bytes, DeserializeValue, SerializeValue, Structure,
+    Type as _, Value, LE,
+  };
+
+  use super::*;
+
+  fn updates() -> [DurationUpdate; 3] {
+    let d = Duration::new(90, 500);
+    [
+      DurationUpdate::Add(d),
+      DurationUpdate::Sub(d),
+      DurationUpdate::Set(d),
+    ]
+  }
+
+  #[test]
+  fn duration_update_signature_is_stable() {
+    assert_eq!(DurationUpdate::signature(), "(u(tu))");
+    assert_eq!(UpdateOptions::signature(), "(bs)");
+  }
+
+  #[test]
+  fn duration_updates_round_trip() {
+    let ctxt = Context::new_dbus(LE, 0);
+    for update in updates() {
+      let data = to_bytes(ctxt, &update).unwrap();
+      let (decoded, _): (DurationUpdate, _) = data.deserialize().unwrap();
+      assert_eq!(decoded, update);
+
+      // as a variant, e.g. in a property
+      let data = to_bytes(ctxt, &SerializeValue(&update)).unwrap();
+      let (decoded, _): (DeserializeValue<DurationUpdate>, _) =
+        data.deserialize().unwrap();
+      assert_eq!(decoded.0, update);
+    }
+  }
+
+  #[test]
+  fn duration_updates_keep_their_index() {
+    let ctxt = Context::new_dbus(LE, 0);
+    for (index, update) in updates().into_iter().enumerate() {
+      let data = to_bytes(ctxt, &SerializeValue(&update)).unwrap();
+      let (value, _): (Value, _) = data.deserialize().unwrap();
+      let Value::Structure(fields) = value else {
+        panic!("{update:?} is not a structure");
+      };
+      let duration = Structure::from((90u64, 500u32));
+      assert_eq!(
+        fields.fields(),
+        [Value::U32(index as u32), Value::Structure(duration)],
+        "{update:?} changed"
+      );
+    }
+  }
+}
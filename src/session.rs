@@ -0,0 +1,41 @@
+use std::{env, fmt};
+
+/// The kind of graphical session we are running in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionType {
+  X11,
+  Wayland,
+  // no display server found, e.g. a tty or ssh login
+  Unknown,
+}
+
+impl SessionType {
+  pub fn detect() -> Self {
+    match env::var("XDG_SESSION_TYPE").as_deref() {
+      Ok("wayland") => return Self::Wayland,
+      Ok("x11") => return Self::X11,
+      _ => {}
+    }
+
+    // a Wayland session may also set $DISPLAY for Xwayland, so check
+    // for Wayland first
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+      Self::Wayland
+    } else if env::var_os("DISPLAY").is_some() {
+      Self::X11
+    } else {
+      Self::Unknown
+    }
+  }
+}
+
+impl fmt::Display for SessionType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::X11 => "x11",
+      Self::Wayland => "wayland",
+      Self::Unknown => "unknown",
+    };
+    f.write_str(name)
+  }
+}
@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use tokio::signal::unix::SignalKind;
 
 pub struct ExitSignals {
@@ -24,3 +26,10 @@ impl ExitSignals {
     }
   }
 }
+
+/// Resolves on the first SIGINT or SIGTERM. The handlers are installed
+/// right away rather than when first polled.
+pub fn exit_signal() -> impl Future<Output = SignalKind> + Send {
+  let mut signals = ExitSignals::new();
+  async move { signals.recv().await }
+}
@@ -0,0 +1,391 @@
+//! Control over a Unix domain socket, for systems without D-Bus.
+//!
+//! Each line is a JSON request; each gets a JSON response line:
+//!
+//! ```text
+//! {"method":"update","update":"+30m"}  -> "ok"
+//! {"method":"status"}                  -> {"status":{...}}
+//! {"method":"subscribe"}               -> {"status":{...}} on each change
+//! ```
+
+use std::{
+  io,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, Lines},
+  net::{unix::OwnedReadHalf, unix::OwnedWriteHalf, UnixListener, UnixStream},
+  sync::{mpsc, oneshot, watch},
+};
+use tracing::{debug, warn};
+
+use crate::{
+  daemon::DaemonMessage,
+  helper,
+  protocol::{DurationUpdate, Instance, Status, UpdateOptions},
+};
+
+/// Where the daemon listens when serving over a socket
+pub fn default_path(instance: &Instance) -> PathBuf {
+  let dir = std::env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(std::env::temp_dir);
+  dir.join(instance.file_name("sock"))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+enum Request {
+  // the update in the same syntax as `vigilare msg`
+  Update {
+    update: String,
+    #[serde(default)]
+    rolling: bool,
+    #[serde(default)]
+    label: String,
+  },
+  ExtendByFactor {
+    factor: f64,
+  },
+  SetMode {
+    mode: String,
+  },
+  Cancel {
+    name: String,
+  },
+  Presets,
+  Status,
+  Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum Response {
+  Ok,
+  Presets(Vec<String>),
+  Status(Status),
+  Error(String),
+}
+
+/// The listening socket, removed again when dropped
+pub struct Listener {
+  listener: UnixListener,
+  path: PathBuf,
+}
+
+impl Listener {
+  pub async fn bind(path: &Path) -> Result<Self> {
+    // a socket nobody answers on is left over from a daemon that died
+    if UnixStream::connect(path).await.is_ok() {
+      bail!(
+        "{} is already served; is another vigilare daemon running?",
+        path.display()
+      );
+    }
+    match std::fs::remove_file(path) {
+      Err(e) if e.kind() != io::ErrorKind::NotFound => {
+        return Err(e).context("failed to remove stale socket");
+      }
+      _ => {}
+    }
+
+    let listener = UnixListener::bind(path)
+      .with_context(|| format!("failed to listen on {}", path.display()))?;
+    Ok(Self {
+      listener,
+      path: path.to_path_buf(),
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    if let Err(e) = std::fs::remove_file(&self.path) {
+      warn!("Failed to remove socket {}: {e}", self.path.display());
+    }
+  }
+}
+
+/// State shared by all socket connections
+#[derive(Clone)]
+pub struct Server {
+  pub sender: mpsc::Sender<DaemonMessage>,
+  // ticks whenever the status changes
+  pub changes: watch::Receiver<()>,
+  // rolling vigils rely on the idle watcher
+  pub can_roll: bool,
+  pub presets: Vec<Duration>,
+}
+
+impl Server {
+  pub async fn serve(self, listener: &Listener) {
+    loop {
+      let stream = match listener.listener.accept().await {
+        Ok((stream, _)) => stream,
+        Err(e) => {
+          warn!("Failed to accept connection: {e}");
+          continue;
+        }
+      };
+
+      let server = self.clone();
+      tokio::spawn(async move {
+        if let Err(e) = server.handle(stream).await {
+          debug!("Socket client went away: {e:#}");
+        }
+      });
+    }
+  }
+
+  async fn handle(mut self, stream: UnixStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+      let request = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => {
+          let response = Response::Error(format!("invalid request: {e}"));
+          send(&mut write, &response).await?;
+          continue;
+        }
+      };
+
+      match request {
+        Request::Update {
+          update,
+          rolling,
+          label,
+        } => {
+          let options = UpdateOptions { rolling, label };
+          let response = match self.update(&update, options).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+          };
+          send(&mut write, &response).await?;
+        }
+        Request::ExtendByFactor { factor } => {
+          let response = match self.extend_by_factor(factor).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+          };
+          send(&mut write, &response).await?;
+        }
+        Request::SetMode { mode } => {
+          let response = match self.set_mode(&mode).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+          };
+          send(&mut write, &response).await?;
+        }
+        Request::Cancel { name } => {
+          let response = match self.cancel(name).await {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(format!("{e:#}")),
+          };
+          send(&mut write, &response).await?;
+        }
+        Request::Presets => {
+          let presets =
+            self.presets.iter().copied().map(helper::format_duration);
+          send(&mut write, &Response::Presets(presets.collect())).await?;
+        }
+        Request::Status => {
+          let status = self.status().await?;
+          send(&mut write, &Response::Status(status)).await?;
+        }
+        Request::Subscribe => loop {
+          self.changes.mark_unchanged();
+          let status = self.status().await?;
+          send(&mut write, &Response::Status(status)).await?;
+          self.changes.changed().await?;
+        },
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn update(&self, update: &str, options: UpdateOptions) -> Result<()> {
+    let update =
+      helper::parse_duration_update(update).map_err(|e| anyhow!(e))?;
+    if options.rolling && !self.can_roll {
+      bail!("rolling vigils need the daemon to run with --idle-timeout");
+    }
+
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::DurationUpdate(update, options, reply))
+      .await?;
+    receiver.await?.map_err(|e| anyhow!(e))
+  }
+
+  async fn extend_by_factor(&self, factor: f64) -> Result<()> {
+    helper::check_factor(factor).map_err(|e| anyhow!(e))?;
+
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::ExtendByFactor(factor, reply))
+      .await?;
+    receiver.await?.map_err(|e| anyhow!(e))
+  }
+
+  async fn set_mode(&self, mode: &str) -> Result<()> {
+    let mode = mode.parse()?;
+
+    let (reply, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::SetMode(mode, reply))
+      .await?;
+    receiver.await?.map_err(|e| anyhow!(e))
+  }
+
+  async fn cancel(&self, name: String) -> Result<()> {
+    let (reply, receiver) = oneshot::channel();
+    self.sender.send(DaemonMessage::Cancel(name, reply)).await?;
+    receiver.await?.map_err(|e| anyhow!(e))
+  }
+
+  async fn status(&self) -> Result<Status> {
+    let (sender, receiver) = oneshot::channel();
+    self
+      .sender
+      .send(DaemonMessage::StatusRequest(sender))
+      .await?;
+    Ok(receiver.await?)
+  }
+}
+
+async fn send(write: &mut OwnedWriteHalf, response: &Response) -> Result<()> {
+  let mut line = serde_json::to_string(response)?;
+  line.push('\n');
+  write.write_all(line.as_bytes()).await?;
+  Ok(())
+}
+
+/// A client connection to a daemon serving over a socket
+pub struct Client {
+  path: PathBuf,
+  lines: Lines<BufReader<OwnedReadHalf>>,
+  write: OwnedWriteHalf,
+}
+
+impl Client {
+  pub async fn connect(path: &Path) -> Result<Self> {
+    let stream = UnixStream::connect(path)
+      .await
+      .with_context(|| format!("failed to connect to {}", path.display()))?;
+    let (read, write) = stream.into_split();
+    Ok(Self {
+      path: path.to_path_buf(),
+      lines: BufReader::new(read).lines(),
+      write,
+    })
+  }
+
+  async fn request(&mut self, request: &Request) -> Result<()> {
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    self.write.write_all(line.as_bytes()).await?;
+    Ok(())
+  }
+
+  async fn response(&mut self) -> Result<Response> {
+    let line = self.lines.next_line().await?.ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the daemon closed the connection",
+      )
+    })?;
+    match serde_json::from_str(&line)? {
+      Response::Error(e) => bail!(e),
+      response => Ok(response),
+    }
+  }
+
+  async fn status_response(&mut self) -> Result<Status> {
+    match self.response().await? {
+      Response::Status(status) => Ok(status),
+      response => bail!("unexpected response {response:?}"),
+    }
+  }
+
+  pub async fn update(
+    &mut self,
+    update: &DurationUpdate,
+    options: UpdateOptions,
+  ) -> Result<()> {
+    let UpdateOptions { rolling, label } = options;
+    let update = update.to_string();
+    let request = Request::Update {
+      update,
+      rolling,
+      label,
+    };
+    self.request(&request).await?;
+    self.response().await?;
+    Ok(())
+  }
+
+  pub async fn extend_by_factor(&mut self, factor: f64) -> Result<()> {
+    self.request(&Request::ExtendByFactor { factor }).await?;
+    self.response().await?;
+    Ok(())
+  }
+
+  pub async fn set_mode(&mut self, mode: &str) -> Result<()> {
+    let mode = mode.to_string();
+    self.request(&Request::SetMode { mode }).await?;
+    self.response().await?;
+    Ok(())
+  }
+
+  pub async fn cancel(&mut self, name: &str) -> Result<()> {
+    let name = name.to_string();
+    self.request(&Request::Cancel { name }).await?;
+    self.response().await?;
+    Ok(())
+  }
+
+  pub async fn presets(&mut self) -> Result<Vec<String>> {
+    self.request(&Request::Presets).await?;
+    match self.response().await? {
+      Response::Presets(presets) => Ok(presets),
+      response => bail!("unexpected response {response:?}"),
+    }
+  }
+
+  pub async fn status(&mut self) -> Result<Status> {
+    self.request(&Request::Status).await?;
+    self.status_response().await
+  }
+
+  /// Open a second connection streaming status updates, starting
+  /// with the current status
+  pub async fn subscribe(&self) -> Result<Subscription> {
+    let mut client = Self::connect(&self.path).await?;
+    client.request(&Request::Subscribe).await?;
+    Ok(Subscription { client })
+  }
+}
+
+pub struct Subscription {
+  client: Client,
+}
+
+impl Subscription {
+  pub async fn next(&mut self) -> Result<Status> {
+    self.client.status_response().await
+  }
+}
@@ -0,0 +1,80 @@
+//! What the daemon keeps across restarts
+
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{inhibitor::InhibitMode, protocol::Instance};
+
+// bumped when a field changes meaning, not when one is added
+const VERSION: u32 = 1;
+
+/// Where the daemon keeps its state
+pub fn default_path(instance: &Instance) -> PathBuf {
+  let dir = std::env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(std::env::temp_dir);
+  dir.join(instance.file_name("state"))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct State {
+  version: u32,
+  // the modes last switched to at runtime
+  #[serde(default)]
+  pub modes: Vec<InhibitMode>,
+  // the modes configured when they were switched, which win again
+  // once they are configured differently
+  #[serde(default)]
+  pub configured: Vec<InhibitMode>,
+  // UNIX epoch time the vigil ends at, like `Status::wake_until`, 0
+  // when there is none or it has no deadline
+  #[serde(default)]
+  pub wake_until: u64,
+  #[serde(default)]
+  pub indefinite: bool,
+}
+
+impl Default for State {
+  fn default() -> Self {
+    Self {
+      version: VERSION,
+      modes: Vec::new(),
+      configured: Vec::new(),
+      wake_until: 0,
+      indefinite: false,
+    }
+  }
+}
+
+impl State {
+  /// Read the state left by the last run, if there is any
+  pub fn load(path: &Path) -> Result<Option<Self>> {
+    let json = match fs::read_to_string(path) {
+      Ok(json) => json,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e).context("failed to read the state file"),
+    };
+
+    let state: Self =
+      serde_json::from_str(&json).context("invalid state file")?;
+    if state.version > VERSION {
+      bail!("state file is from a newer version ({})", state.version);
+    }
+    Ok(Some(state))
+  }
+
+  /// Write the state, replacing the file in one go so that a crash
+  /// can't leave half of it behind
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let json = serde_json::to_string(self)?;
+    let tmp = path.with_extension("state.tmp");
+    fs::write(&tmp, json)
+      .and_then(|()| fs::rename(&tmp, path))
+      .with_context(|| format!("failed to write {}", path.display()))
+  }
+}
@@ -0,0 +1,170 @@
+//! Templates for the monitor output and notifications, like
+//! "{remaining} ({state})"
+
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+/// What a placeholder stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+  // the status message, e.g. "25m"
+  Remaining,
+  RemainingSeconds,
+  // active, indefinite or inactive
+  State,
+  // the local time the vigil ends at, as HH:MM
+  WakeUntilLocal,
+  Label,
+  // the inhibit modes in effect, only known to the daemon
+  Mode,
+}
+
+type Fields = &'static [(&'static str, Field)];
+
+impl Field {
+  // what the status tells the monitor
+  const STATUS: Fields = &[
+    ("remaining", Self::Remaining),
+    ("remaining_seconds", Self::RemainingSeconds),
+    ("state", Self::State),
+    ("wake_until_local", Self::WakeUntilLocal),
+    ("label", Self::Label),
+  ];
+  // the same and what else the daemon knows, for notifications
+  const NOTIFICATION: Fields = &[
+    ("remaining", Self::Remaining),
+    ("remaining_seconds", Self::RemainingSeconds),
+    ("state", Self::State),
+    ("wake_until_local", Self::WakeUntilLocal),
+    ("label", Self::Label),
+    ("mode", Self::Mode),
+  ];
+
+  fn name(self) -> &'static str {
+    Self::NOTIFICATION
+      .iter()
+      .find(|(_, field)| *field == self)
+      .map_or("", |(name, _)| *name)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+  Literal(String),
+  Field(Field),
+}
+
+/// A parsed template. "{{" and "}}" stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Template {
+  pieces: Vec<Piece>,
+}
+
+impl Template {
+  /// A template for the monitor
+  pub fn parse(s: &str) -> Result<Self, String> {
+    Self::parse_with(s, Field::STATUS)
+  }
+
+  /// A template for notifications, which can also name the mode
+  pub fn parse_notification(s: &str) -> Result<Self, String> {
+    Self::parse_with(s, Field::NOTIFICATION)
+  }
+
+  fn parse_with(s: &str, fields: Fields) -> Result<Self, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+      match c {
+        '{' if chars.as_str().starts_with('{') => {
+          chars.next();
+          literal.push('{');
+        }
+        '}' if chars.as_str().starts_with('}') => {
+          chars.next();
+          literal.push('}');
+        }
+        '{' => {
+          let rest = chars.as_str();
+          let Some(end) = rest.find('}') else {
+            return Err("unclosed \"{\" in template".to_string());
+          };
+          let name = &rest[..end];
+          let field = fields.iter().find(|(n, _)| *n == name);
+          let (_, field) = field.ok_or_else(|| {
+            let names: Vec<_> = fields.iter().map(|(n, _)| *n).collect();
+            format!(
+              "unknown placeholder {{{name}}}, try one of {}",
+              names.join(", ")
+            )
+          })?;
+          chars = rest[end + 1..].chars();
+
+          if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+          }
+          pieces.push(Piece::Field(*field));
+        }
+        '}' => return Err("unmatched \"}\" in template".to_string()),
+        c => literal.push(c),
+      }
+    }
+
+    if !literal.is_empty() {
+      pieces.push(Piece::Literal(literal));
+    }
+    Ok(Self { pieces })
+  }
+
+  /// Fill in the placeholders with what `value` gives for them
+  pub fn render(&self, mut value: impl FnMut(Field) -> String) -> String {
+    let mut out = String::new();
+    for piece in &self.pieces {
+      match piece {
+        Piece::Literal(s) => out.push_str(s),
+        Piece::Field(field) => out.push_str(&value(*field)),
+      }
+    }
+    out
+  }
+}
+
+// back in the syntax it was parsed from
+impl fmt::Display for Template {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for piece in &self.pieces {
+      match piece {
+        Piece::Literal(s) => {
+          write!(f, "{}", s.replace('{', "{{").replace('}', "}}"))?
+        }
+        Piece::Field(field) => write!(f, "{{{}}}", field.name())?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Serialize for Template {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+/// A UNIX epoch time as the local time of day, e.g. "14:05"
+pub fn local_time(epoch: u64) -> String {
+  let Ok(time) = libc::time_t::try_from(epoch) else {
+    return String::new();
+  };
+  // SAFETY: localtime_r only writes to the struct it's given
+  let tm = unsafe {
+    let mut tm = std::mem::zeroed::<libc::tm>();
+    if libc::localtime_r(&time, &mut tm).is_null() {
+      return String::new();
+    }
+    tm
+  };
+  format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+}